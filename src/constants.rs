@@ -9,4 +9,16 @@ pub const RTP_H264_RTX_PROFILE_TYPE: RtpType = RtpType::Dynamic(104);
 pub const RTP_VP8_PROFILE_TYPE: RtpType = RtpType::Dynamic(105);
 pub const RTP_VP8_RTX_PROFILE_TYPE: RtpType = RtpType::Dynamic(106);
 pub const RTP_VP9_PROFILE_TYPE: RtpType = RtpType::Dynamic(107);
-pub const RTP_VP9_RTX_PROFILE_TYPE: RtpType = RtpType::Dynamic(108);
\ No newline at end of file
+pub const RTP_VP9_RTX_PROFILE_TYPE: RtpType = RtpType::Dynamic(108);
+/// Default maximum size of a datagram sent to the voice server.
+pub const DEFAULT_MTU: usize = 1200;
+/// Smallest MTU which leaves room for the RTP header, the largest encryption
+/// overhead and any payload descriptor.
+pub const MIN_MTU: usize = 128;
+/// Largest UDP payload over IPv4.
+pub const MAX_MTU: usize = 65_507;
+
+/// Rate of the RTP timestamp clock for all video codecs.
+pub const VIDEO_CLOCK_RATE: u64 = 90_000;
+/// Rate of the RTP timestamp clock for Opus.
+pub const OPUS_CLOCK_RATE: u64 = 48_000;
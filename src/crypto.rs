@@ -1,20 +1,92 @@
 // https://github.com/serenity-rs/songbird/blob/current/src/driver/crypto.rs
 //! Encryption schemes supported by Discord's secure RTP negotiation.
+use aes_gcm::Aes256Gcm;
+use base64::Engine;
+use chacha20poly1305::XChaCha20Poly1305;
 use byteorder::{NetworkEndian, WriteBytesExt};
 use discortp::{rtp::RtpPacket, MutablePacket};
+use gst::glib;
 use rand::Rng;
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
 use std::num::Wrapping;
+use std::ops::Range;
 use xsalsa20poly1305::{
-    aead::{AeadInPlace, Error as CryptoError},
+    aead::{AeadInPlace, Error as CryptoError, KeyInit},
     Nonce,
-    XSalsa20Poly1305 as Cipher,
+    XSalsa20Poly1305,
+    KEY_SIZE,
     NONCE_SIZE,
     TAG_SIZE,
 };
 use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
 
-/// Variants of the XSalsa20Poly1305 encryption scheme.
-#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+/// Size of the incrementing nonce stored after the payload by the `lite` and
+/// AEAD schemes.
+const COUNTER_NONCE_SIZE: usize = 4;
+
+/// Number of recent nonces remembered by [`NonceReuseDetector`], one cycle of RTP
+/// sequence numbers.
+const NONCE_HISTORY_SIZE: usize = 1 << 16;
+
+/// Size of an RTP header extension preamble, which `rtpsize` modes leave unencrypted.
+const RTP_EXTENSION_PREAMBLE_SIZE: usize = 4;
+
+/// Reasons a packet could not be decrypted.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DecryptError {
+    /// The packet ends before its nonce.
+    MissingNonce,
+    /// The packet is too short to hold the authentication tag.
+    ShortPacket,
+    /// The authentication tag does not match the packet contents.
+    BadTag,
+    /// The cipher was created for a different mode.
+    MismatchedCipher,
+}
+
+impl fmt::Display for DecryptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecryptError::MissingNonce => write!(f, "packet too short to hold the nonce"),
+            DecryptError::ShortPacket => write!(f, "packet too short to hold the authentication tag"),
+            DecryptError::BadTag => write!(f, "authentication tag mismatch"),
+            DecryptError::MismatchedCipher => write!(f, "cipher does not match the crypto mode"),
+        }
+    }
+}
+
+impl std::error::Error for DecryptError {}
+
+/// Reasons a secret key was rejected.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum KeyError {
+    /// The text is neither hex, base64 nor a JSON array.
+    UnknownEncoding,
+    /// The JSON array holds something other than integers from 0 to 255.
+    InvalidByteArray,
+    /// The key does not have [`KEY_SIZE`] bytes, holds the actual size.
+    WrongLength(usize),
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyError::UnknownEncoding => write!(f, "key is not hex, base64 or a JSON array of bytes"),
+            KeyError::InvalidByteArray => write!(f, "JSON key must be an array of integers from 0 to 255"),
+            KeyError::WrongLength(len) => write!(f, "key must be {} bytes long, got {} bytes", KEY_SIZE, len),
+        }
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+/// Encryption schemes supported by Discord voice servers.
+///
+/// Registered as a GLib enum whose value nicks are the mode names used by Discord.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize, glib::Enum)]
+#[enum_type(name = "GstDiscordStreamerCryptoMode")]
 #[non_exhaustive]
 pub enum CryptoMode {
     /// The RTP header is used as the source of nonce bytes for the packet.
@@ -22,19 +94,35 @@ pub enum CryptoMode {
     /// Equivalent to a nonce of at most 48b (6B) at no extra packet overhead:
     /// the RTP sequence number and timestamp are the varying quantities.
     #[serde(rename = "xsalsa20_poly1305")]
+    #[enum_value(name = "XSalsa20-Poly1305 with the RTP header as nonce", nick = "xsalsa20_poly1305")]
     Normal,
     /// An additional random 24B suffix is used as the source of nonce bytes for the packet.
     /// This is regenerated randomly for each packet.
     ///
     /// Full nonce width of 24B (192b), at an extra 24B per packet (~1.2 kB/s).
     #[serde(rename = "xsalsa20_poly1305_suffix")]
+    #[enum_value(name = "XSalsa20-Poly1305 with a random nonce suffix", nick = "xsalsa20_poly1305_suffix")]
     Suffix,
     /// An additional random 4B suffix is used as the source of nonce bytes for the packet.
     /// This nonce value increments by `1` with each packet.
     ///
     /// Nonce width of 4B (32b), at an extra 4B per packet (~0.2 kB/s).
     #[serde(rename = "xsalsa20_poly1305_lite")]
+    #[enum_value(name = "XSalsa20-Poly1305 with an incrementing nonce suffix", nick = "xsalsa20_poly1305_lite")]
     Lite,
+    /// AES-256-GCM with a 4B incrementing nonce suffix, zero-padded to 12B.
+    ///
+    /// The RTP header up to and including any extension preamble is authenticated
+    /// but left unencrypted, the 16B tag follows the encrypted payload.
+    #[serde(rename = "aead_aes256_gcm_rtpsize")]
+    #[enum_value(name = "AES-256-GCM", nick = "aead_aes256_gcm_rtpsize")]
+    Aes256Gcm,
+    /// XChaCha20-Poly1305 with a 4B incrementing nonce suffix, zero-padded to 24B.
+    ///
+    /// Laid out like [`CryptoMode::Aes256Gcm`], every Discord voice server supports it.
+    #[serde(rename = "aead_xchacha20_poly1305_rtpsize")]
+    #[enum_value(name = "XChaCha20-Poly1305", nick = "aead_xchacha20_poly1305_rtpsize")]
+    XChaCha20Poly1305,
 }
 
 impl From<CryptoState> for CryptoMode {
@@ -44,11 +132,36 @@ impl From<CryptoState> for CryptoMode {
             Normal => CryptoMode::Normal,
             Suffix => CryptoMode::Suffix,
             Lite(_) => CryptoMode::Lite,
+            Aes256Gcm(_) => CryptoMode::Aes256Gcm,
+            XChaCha20Poly1305(_) => CryptoMode::XChaCha20Poly1305,
         }
     }
 }
 
 impl CryptoMode {
+    /// Supported modes, most preferred first.
+    ///
+    /// The AEAD modes are the ones current voice servers are built around, the
+    /// XSalsa20 ones are deprecated and only used when nothing else is offered.
+    pub const PREFERENCE_ORDER: [CryptoMode; 5] = [
+        CryptoMode::Aes256Gcm,
+        CryptoMode::XChaCha20Poly1305,
+        CryptoMode::Lite,
+        CryptoMode::Suffix,
+        CryptoMode::Normal,
+    ];
+
+    /// Picks the most preferred supported mode among the mode names offered by
+    /// the voice server, ignoring unknown names.
+    pub fn negotiate<'a>(offered: impl IntoIterator<Item = &'a str>) -> Option<Self> {
+        let offered: Vec<CryptoMode> = offered
+            .into_iter()
+            .filter_map(|name| serde_plain::from_str(name).ok())
+            .collect();
+
+        Self::PREFERENCE_ORDER.into_iter().find(|mode| offered.contains(mode))
+    }
+
     /// Returns the number of bytes each nonce is stored as within
     /// a packet.
     pub fn nonce_size(self) -> usize {
@@ -56,14 +169,28 @@ impl CryptoMode {
         match self {
             Normal => RtpPacket::minimum_packet_size(),
             Suffix => NONCE_SIZE,
-            Lite => 4,
+            Lite | Aes256Gcm | XChaCha20Poly1305 => COUNTER_NONCE_SIZE,
         }
     }
 
     /// Returns the number of bytes occupied by the encryption scheme
     /// which fall before the payload.
     pub fn payload_prefix_len(self) -> usize {
-        TAG_SIZE
+        use CryptoMode::*;
+        match self {
+            Normal | Suffix | Lite => TAG_SIZE,
+            Aes256Gcm | XChaCha20Poly1305 => 0,
+        }
+    }
+
+    /// Returns the number of bytes occupied by the authentication tag
+    /// directly after the payload.
+    fn tag_suffix_len(self) -> usize {
+        use CryptoMode::*;
+        match self {
+            Normal | Suffix | Lite => 0,
+            Aes256Gcm | XChaCha20Poly1305 => TAG_SIZE,
+        }
     }
 
     /// Returns the number of bytes occupied by the encryption scheme
@@ -72,7 +199,7 @@ impl CryptoMode {
         use CryptoMode::*;
         match self {
             Normal => 0,
-            Suffix | Lite => self.nonce_size(),
+            Suffix | Lite | Aes256Gcm | XChaCha20Poly1305 => self.tag_suffix_len() + self.nonce_size(),
         }
     }
 
@@ -82,6 +209,35 @@ impl CryptoMode {
         self.payload_prefix_len() + self.payload_suffix_len()
     }
 
+    /// Returns whether the scheme authenticates the unencrypted RTP header,
+    /// including the extension preamble.
+    fn is_rtpsize(self) -> bool {
+        matches!(self, CryptoMode::Aes256Gcm | CryptoMode::XChaCha20Poly1305)
+    }
+
+    /// Returns the length of the packet prefix which is left unencrypted.
+    ///
+    /// `rtpsize` schemes keep the preamble of an RTP header extension in the clear,
+    /// RTCP packets are recognised by their packet type and never carry one.
+    fn unencrypted_len(self, packet: &[u8], header_len: usize) -> usize {
+        let is_rtcp = matches!(packet.get(1), Some(192..=223));
+        let has_extension = matches!(packet.first(), Some(b) if b & 0b0001_0000 != 0);
+
+        if self.is_rtpsize() && !is_rtcp && has_extension {
+            header_len + RTP_EXTENSION_PREAMBLE_SIZE
+        } else {
+            header_len
+        }
+    }
+
+    /// Returns the nonce bytes of an encrypted packet.
+    fn packet_nonce(self, packet: &[u8]) -> &[u8] {
+        match self {
+            CryptoMode::Normal => &packet[..self.nonce_size()],
+            _ => &packet[packet.len() - self.nonce_size()..],
+        }
+    }
+
     /// Extracts the byte slice in a packet used as the nonce, and the remaining mutable
     /// portion of the packet.
     fn nonce_slice<'a>(
@@ -92,12 +248,12 @@ impl CryptoMode {
         use CryptoMode::*;
         match self {
             Normal => Ok((header, body)),
-            Suffix | Lite => {
+            Suffix | Lite | Aes256Gcm | XChaCha20Poly1305 => {
                 let len = body.len();
                 if len < self.payload_suffix_len() {
                     Err(CryptoError)
                 } else {
-                    let (body_left, nonce_loc) = body.split_at_mut(len - self.payload_suffix_len());
+                    let (body_left, nonce_loc) = body.split_at_mut(len - self.nonce_size());
                     Ok((&nonce_loc[..self.nonce_size()], body_left))
                 }
             },
@@ -116,25 +272,194 @@ impl CryptoMode {
         payload_len: usize,
     ) -> Result<(), CryptoError> {
         let header_len = packet.packet().len() - packet.payload().len();
-        let (header, body) = packet.packet_mut().split_at_mut(header_len);
+        let unencrypted_len = self.unencrypted_len(packet.packet(), header_len);
+        let payload_len = payload_len
+            .checked_sub(unencrypted_len - header_len)
+            .ok_or(CryptoError)?;
+
+        let (header, body) = packet.packet_mut().split_at_mut(unencrypted_len);
         let (slice_to_use, body_remaining) = self.nonce_slice(header, &mut body[..payload_len])?;
 
-        let mut nonce = Nonce::default();
-        let nonce_slice = if slice_to_use.len() == NONCE_SIZE {
-            Nonce::from_slice(&slice_to_use[..NONCE_SIZE])
-        } else {
-            nonce[..self.nonce_size()].copy_from_slice(slice_to_use);
-            &nonce
-        };
+        match (self, cipher) {
+            (CryptoMode::Normal | CryptoMode::Suffix | CryptoMode::Lite, Cipher::XSalsa20Poly1305(cipher)) => {
+                let mut nonce = Nonce::default();
+                let nonce_slice = if slice_to_use.len() == NONCE_SIZE {
+                    Nonce::from_slice(&slice_to_use[..NONCE_SIZE])
+                } else {
+                    nonce[..self.nonce_size()].copy_from_slice(slice_to_use);
+                    &nonce
+                };
 
-        // body_remaining is now correctly truncated by this point.
-        // the true_payload to encrypt follows after the first TAG_LEN bytes.
-        let tag =
-            cipher.encrypt_in_place_detached(nonce_slice, b"", &mut body_remaining[TAG_SIZE..])?;
-        body_remaining[..TAG_SIZE].copy_from_slice(&tag[..]);
+                // body_remaining is now correctly truncated by this point.
+                // the true_payload to encrypt follows after the first TAG_LEN bytes.
+                let tag =
+                    cipher.encrypt_in_place_detached(nonce_slice, b"", &mut body_remaining[TAG_SIZE..])?;
+                body_remaining[..TAG_SIZE].copy_from_slice(&tag[..]);
+            },
+            (CryptoMode::Aes256Gcm, Cipher::Aes256Gcm(cipher)) => {
+                let mut nonce = aes_gcm::Nonce::default();
+                nonce[..COUNTER_NONCE_SIZE].copy_from_slice(slice_to_use);
+
+                // The tag follows the encrypted payload.
+                let (plaintext, tag_loc) = body_remaining.split_at_mut(body_remaining.len() - TAG_SIZE);
+                let tag = cipher.encrypt_in_place_detached(&nonce, header, plaintext)?;
+                tag_loc.copy_from_slice(&tag[..]);
+            },
+            (CryptoMode::XChaCha20Poly1305, Cipher::XChaCha20Poly1305(cipher)) => {
+                let mut nonce = chacha20poly1305::XNonce::default();
+                nonce[..COUNTER_NONCE_SIZE].copy_from_slice(slice_to_use);
+
+                let (plaintext, tag_loc) = body_remaining.split_at_mut(body_remaining.len() - TAG_SIZE);
+                let tag = cipher.encrypt_in_place_detached(&nonce, header, plaintext)?;
+                tag_loc.copy_from_slice(&tag[..]);
+            },
+            _ => return Err(CryptoError),
+        }
 
         Ok(())
     }
+
+    /// Decrypts a Discord RT(C)P packet using the given key.
+    ///
+    /// On success, returns the range of `packet.payload()` holding the plaintext payload,
+    /// for `rtpsize` schemes this includes the extension preamble.
+    pub fn decrypt_in_place(
+        self,
+        packet: &mut impl MutablePacket,
+        cipher: &Cipher,
+    ) -> Result<Range<usize>, DecryptError> {
+        let header_len = packet.packet().len() - packet.payload().len();
+        let unencrypted_len = self.unencrypted_len(packet.packet(), header_len);
+        if packet.packet().len() < unencrypted_len {
+            return Err(DecryptError::ShortPacket);
+        }
+        let preamble_len = unencrypted_len - header_len;
+
+        let (header, body) = packet.packet_mut().split_at_mut(unencrypted_len);
+        let header = &*header;
+        let (nonce_bytes, body) = match self {
+            CryptoMode::Normal => (&header[..self.nonce_size()], body),
+            _ => {
+                let nonce_start = body
+                    .len()
+                    .checked_sub(self.nonce_size())
+                    .ok_or(DecryptError::MissingNonce)?;
+                let (body, nonce) = body.split_at_mut(nonce_start);
+                (&*nonce, body)
+            },
+        };
+
+        if body.len() < TAG_SIZE {
+            return Err(DecryptError::ShortPacket);
+        }
+        let ciphertext_len = body.len() - TAG_SIZE;
+
+        match (self, cipher) {
+            (CryptoMode::Normal | CryptoMode::Suffix | CryptoMode::Lite, Cipher::XSalsa20Poly1305(cipher)) => {
+                let mut nonce = Nonce::default();
+                nonce[..nonce_bytes.len()].copy_from_slice(nonce_bytes);
+
+                let (tag, ciphertext) = body.split_at_mut(TAG_SIZE);
+                cipher
+                    .decrypt_in_place_detached(&nonce, b"", ciphertext, xsalsa20poly1305::Tag::from_slice(tag))
+                    .map_err(|_| DecryptError::BadTag)?;
+
+                Ok(TAG_SIZE..TAG_SIZE + ciphertext_len)
+            },
+            (CryptoMode::Aes256Gcm, Cipher::Aes256Gcm(cipher)) => {
+                let mut nonce = aes_gcm::Nonce::default();
+                nonce[..COUNTER_NONCE_SIZE].copy_from_slice(nonce_bytes);
+
+                let (ciphertext, tag) = body.split_at_mut(ciphertext_len);
+                cipher
+                    .decrypt_in_place_detached(&nonce, header, ciphertext, aes_gcm::Tag::from_slice(tag))
+                    .map_err(|_| DecryptError::BadTag)?;
+
+                Ok(0..preamble_len + ciphertext_len)
+            },
+            (CryptoMode::XChaCha20Poly1305, Cipher::XChaCha20Poly1305(cipher)) => {
+                let mut nonce = chacha20poly1305::XNonce::default();
+                nonce[..COUNTER_NONCE_SIZE].copy_from_slice(nonce_bytes);
+
+                let (ciphertext, tag) = body.split_at_mut(ciphertext_len);
+                cipher
+                    .decrypt_in_place_detached(&nonce, header, ciphertext, chacha20poly1305::Tag::from_slice(tag))
+                    .map_err(|_| DecryptError::BadTag)?;
+
+                Ok(0..preamble_len + ciphertext_len)
+            },
+            _ => Err(DecryptError::MismatchedCipher),
+        }
+    }
+}
+
+/// Keyed cipher backing one of the [`CryptoMode`]s.
+///
+/// All ciphers wipe their key schedule when dropped.
+pub enum Cipher {
+    XSalsa20Poly1305(XSalsa20Poly1305),
+    Aes256Gcm(Box<Aes256Gcm>),
+    XChaCha20Poly1305(XChaCha20Poly1305),
+}
+
+impl Cipher {
+    /// Creates the cipher used by `mode` from a [`KEY_SIZE`] byte secret key.
+    pub fn new(mode: CryptoMode, key: &[u8]) -> Result<Self, CryptoError> {
+        if key.len() != KEY_SIZE {
+            return Err(CryptoError);
+        }
+
+        use CryptoMode::*;
+        Ok(match mode {
+            Normal | Suffix | Lite => Cipher::XSalsa20Poly1305(
+                XSalsa20Poly1305::new_from_slice(key).map_err(|_| CryptoError)?,
+            ),
+            Aes256Gcm => Cipher::Aes256Gcm(Box::new(
+                aes_gcm::Aes256Gcm::new_from_slice(key).map_err(|_| CryptoError)?,
+            )),
+            XChaCha20Poly1305 => Cipher::XChaCha20Poly1305(
+                chacha20poly1305::XChaCha20Poly1305::new_from_slice(key).map_err(|_| CryptoError)?,
+            ),
+        })
+    }
+}
+
+impl fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Cipher::XSalsa20Poly1305(_) => "XSalsa20Poly1305",
+            Cipher::Aes256Gcm(_) => "Aes256Gcm",
+            Cipher::XChaCha20Poly1305(_) => "XChaCha20Poly1305",
+        };
+        f.debug_tuple(name).finish_non_exhaustive()
+    }
+}
+
+/// Checks that `key` can be used as the secret key of any [`CryptoMode`].
+pub fn validate_key(key: &[u8]) -> Result<(), KeyError> {
+    if key.len() != KEY_SIZE {
+        return Err(KeyError::WrongLength(key.len()));
+    }
+    Ok(())
+}
+
+/// Parses a secret key given as hex, base64 or the JSON integer array found in
+/// the session description, e.g. `[211, 14, ...]`.
+///
+/// Errors never include parts of the key.
+pub fn parse_key(text: &str) -> Result<Zeroizing<Vec<u8>>, KeyError> {
+    let text = text.trim();
+
+    let key = if text.starts_with('[') {
+        Zeroizing::new(serde_json::from_str::<Vec<u8>>(text).map_err(|_| KeyError::InvalidByteArray)?)
+    } else if text.bytes().all(|c| c.is_ascii_hexdigit()) {
+        Zeroizing::new(hex::decode(text).map_err(|_| KeyError::UnknownEncoding)?)
+    } else {
+        Zeroizing::new(base64::engine::general_purpose::STANDARD.decode(text).map_err(|_| KeyError::UnknownEncoding)?)
+    };
+
+    validate_key(&key)?;
+    Ok(key)
 }
 
 #[allow(missing_docs)]
@@ -144,6 +469,8 @@ pub enum CryptoState {
     Normal,
     Suffix,
     Lite(Wrapping<u32>),
+    Aes256Gcm(Wrapping<u32>),
+    XChaCha20Poly1305(Wrapping<u32>),
 }
 
 impl From<CryptoMode> for CryptoState {
@@ -153,6 +480,8 @@ impl From<CryptoMode> for CryptoState {
             Normal => CryptoState::Normal,
             Suffix => CryptoState::Suffix,
             Lite => CryptoState::Lite(Wrapping(rand::random::<u32>())),
+            Aes256Gcm => CryptoState::Aes256Gcm(Wrapping(rand::random::<u32>())),
+            XChaCha20Poly1305 => CryptoState::XChaCha20Poly1305(Wrapping(rand::random::<u32>())),
         }
     }
 }
@@ -165,20 +494,21 @@ impl CryptoState {
         payload_end: usize,
     ) -> usize {
         let mode = self.kind();
+        let nonce_start = payload_end + mode.tag_suffix_len();
         let endpoint = payload_end + mode.payload_suffix_len();
 
         use CryptoState::*;
         match self {
             Suffix => {
-                rand::thread_rng().fill(&mut packet.payload_mut()[payload_end..endpoint]);
+                rand::thread_rng().fill(&mut packet.payload_mut()[nonce_start..endpoint]);
             },
-            Lite(mut i) => {
-                (&mut packet.payload_mut()[payload_end..endpoint])
+            Lite(i) | Aes256Gcm(i) | XChaCha20Poly1305(i) => {
+                (&mut packet.payload_mut()[nonce_start..endpoint])
                     .write_u32::<NetworkEndian>(i.0)
                     .expect(
-                        "Nonce size is guaranteed to be sufficient to write u32 for lite tagging.",
+                        "Nonce size is guaranteed to be sufficient to write u32 for counter tagging.",
                     );
-                i += Wrapping(1);
+                *i += Wrapping(1);
             },
             _ => {},
         }
@@ -190,4 +520,39 @@ impl CryptoState {
     pub fn kind(&self) -> CryptoMode {
         CryptoMode::from(*self)
     }
-}
\ No newline at end of file
+}
+
+/// Detects nonces used more than once under the same key, which leaks the key stream.
+///
+/// Remembers the nonces of the most recent packets and, for the counter based modes,
+/// how many packets were sent since their 32 bit counter wraps around.
+#[derive(Default)]
+pub struct NonceReuseDetector {
+    seen: HashSet<Vec<u8>>,
+    history: VecDeque<Vec<u8>>,
+    packets: u64,
+}
+
+impl NonceReuseDetector {
+    /// Records the nonce of an encrypted packet, returning `false` if it was used before.
+    pub fn check(&mut self, mode: CryptoMode, packet: &[u8]) -> bool {
+        self.packets += 1;
+        if mode.nonce_size() == COUNTER_NONCE_SIZE && self.packets > 1 << 32 {
+            return false;
+        }
+
+        let nonce = mode.packet_nonce(packet).to_vec();
+        if !self.seen.insert(nonce.clone()) {
+            return false;
+        }
+
+        self.history.push_back(nonce);
+        if self.history.len() > NONCE_HISTORY_SIZE {
+            if let Some(oldest) = self.history.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+
+        true
+    }
+}
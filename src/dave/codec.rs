@@ -0,0 +1,189 @@
+//! Parts of encoded frames which DAVE leaves unencrypted.
+//!
+//! Packetizers and media servers still need to parse these, everything else
+//! of a frame is encrypted.
+use std::borrow::Cow;
+use std::ops::Range;
+
+use discortp::rtp::RtpType;
+
+use crate::constants::{RTP_AV1_PROFILE_TYPE, RTP_H264_PROFILE_TYPE, RTP_OPUS_PROFILE_TYPE, RTP_VP8_PROFILE_TYPE, RTP_VP9_PROFILE_TYPE};
+use crate::packetizer::av1::{self, Obu, OBU_HAS_SIZE_FIELD, OBU_PADDING, OBU_TEMPORAL_DELIMITER, OBU_TILE_LIST};
+use crate::packetizer::h264::{self, NAL_TYPE_MASK};
+
+const H264_NAL_TYPE_SLICE: u8 = 1;
+const H264_NAL_TYPE_IDR: u8 = 5;
+
+const H264_LONG_START_CODE: [u8; 4] = [0, 0, 0, 1];
+
+/// Number of Exp-Golomb coded slice header fields up to and including `pic_parameter_set_id`.
+const H264_SLICE_HEADER_PPS_FIELDS: usize = 3;
+
+/// Unencrypted bytes at the start of a VP8 keyframe: frame tag, start code and dimensions.
+const VP8_KEYFRAME_HEADER_SIZE: usize = 10;
+/// Unencrypted bytes at the start of a VP8 inter frame.
+const VP8_INTER_FRAME_HEADER_SIZE: usize = 1;
+
+/// Codec of the frames passed to the frame encryptor.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Codec {
+    Opus,
+    H264,
+    Vp8,
+    Vp9,
+    Av1,
+}
+
+impl Codec {
+    /// Returns the codec sent with the given Discord payload type.
+    pub fn for_payload_type(payload_type: RtpType) -> Option<Self> {
+        [
+            (RTP_OPUS_PROFILE_TYPE, Codec::Opus),
+            (RTP_H264_PROFILE_TYPE, Codec::H264),
+            (RTP_VP8_PROFILE_TYPE, Codec::Vp8),
+            (RTP_VP9_PROFILE_TYPE, Codec::Vp9),
+            (RTP_AV1_PROFILE_TYPE, Codec::Av1),
+        ]
+        .into_iter()
+        .find(|(candidate, _)| *candidate == payload_type)
+        .map(|(_, codec)| codec)
+    }
+
+    /// Prepares a frame for encryption, returning the frame to encrypt and its
+    /// sorted, non-overlapping unencrypted ranges.
+    pub(super) fn unencrypted_ranges<'a>(self, frame: &'a [u8]) -> (Cow<'a, [u8]>, Vec<Range<usize>>) {
+        match self {
+            Codec::Opus | Codec::Vp9 => (Cow::Borrowed(frame), Vec::new()),
+            Codec::H264 => h264_unencrypted_ranges(frame),
+            Codec::Vp8 => {
+                let keyframe = matches!(frame.first(), Some(tag) if tag & 1 == 0);
+                let header_size = if keyframe { VP8_KEYFRAME_HEADER_SIZE } else { VP8_INTER_FRAME_HEADER_SIZE };
+                let mut ranges = Vec::new();
+                push_range(&mut ranges, 0..header_size.min(frame.len()));
+                (Cow::Borrowed(frame), ranges)
+            }
+            Codec::Av1 => {
+                let (frame, ranges) = av1_unencrypted_ranges(frame);
+                (Cow::Owned(frame), ranges)
+            }
+        }
+    }
+
+    /// Whether an encrypted frame can still be packetized like the original one.
+    ///
+    /// H.264 packetization splits at start codes, the ciphertext and trailer must
+    /// neither form new ones nor end NAL units in zero bytes which would be taken
+    /// as part of the following start code.
+    pub(super) fn is_packetizable(self, plain: &[u8], encrypted: &[u8], encrypted_ranges: &[Range<usize>]) -> bool {
+        match self {
+            Codec::H264 => {
+                count_start_codes(plain) == count_start_codes(encrypted)
+                    && encrypted_ranges.iter().all(|range| range.is_empty() || encrypted[range.end - 1] != 0)
+            }
+            _ => true,
+        }
+    }
+}
+
+/// Rewrites an access unit with four byte start codes and without trailing zero
+/// bytes, the form receivers rebuild it in from the RTP packets.
+///
+/// Keeps start codes and NAL unit headers of all NAL units in the clear, as well as
+/// non-VCL NAL units and the slice header fields up to the picture parameter set id.
+fn h264_unencrypted_ranges(frame: &[u8]) -> (Cow<'_, [u8]>, Vec<Range<usize>>) {
+    let mut ranges = Vec::new();
+
+    // Packetized as a single NAL unit.
+    let start_codes = count_start_codes(frame);
+    if start_codes == 0 {
+        push_range(&mut ranges, 0..h264_unencrypted_size(frame));
+        return (Cow::Borrowed(frame), ranges);
+    }
+
+    let mut rewritten = Vec::with_capacity(frame.len() + start_codes);
+    for unit in h264::split_nal_units(frame) {
+        let start = rewritten.len();
+        rewritten.extend_from_slice(&H264_LONG_START_CODE);
+        push_range(&mut ranges, start..start + H264_LONG_START_CODE.len() + h264_unencrypted_size(unit));
+        rewritten.extend_from_slice(unit);
+    }
+    (Cow::Owned(rewritten), ranges)
+}
+
+/// Returns the number of bytes at the start of a NAL unit which stay unencrypted.
+fn h264_unencrypted_size(unit: &[u8]) -> usize {
+    let Some(&header) = unit.first() else {
+        return 0;
+    };
+
+    match header & NAL_TYPE_MASK {
+        H264_NAL_TYPE_SLICE | H264_NAL_TYPE_IDR => bytes_covering_pps(&unit[1..]).map_or(unit.len(), |size| 1 + size),
+        _ => unit.len(),
+    }
+}
+
+/// Returns the number of bytes covering the slice header up to `pic_parameter_set_id`.
+fn bytes_covering_pps(slice: &[u8]) -> Option<usize> {
+    let mut position = 0;
+    let bit = |position: usize| slice.get(position / 8).map(|byte| (byte >> (7 - position % 8)) & 1);
+
+    for _ in 0..H264_SLICE_HEADER_PPS_FIELDS {
+        // Exp-Golomb: leading zeros, a one and as many bits as there were zeros.
+        let mut leading_zeros = 0;
+        while bit(position)? == 0 {
+            leading_zeros += 1;
+            position += 1;
+        }
+        position += 1 + leading_zeros;
+    }
+
+    let size = position.div_ceil(8);
+    (size <= slice.len()).then_some(size)
+}
+
+fn count_start_codes(data: &[u8]) -> usize {
+    data.windows(3).filter(|window| window == &[0, 0, 1]).count()
+}
+
+/// Rewrites a temporal unit so that only OBU payloads are encrypted.
+///
+/// OBUs which are never sent are dropped and the last OBU loses its size field,
+/// so that it extends over the trailer appended after encryption.
+fn av1_unencrypted_ranges(data: &[u8]) -> (Vec<u8>, Vec<Range<usize>>) {
+    let obus: Vec<Obu> = av1::parse_obus(data)
+        .into_iter()
+        .filter(|obu| !matches!(av1::obu_type(obu.header[0]), OBU_TEMPORAL_DELIMITER | OBU_TILE_LIST | OBU_PADDING))
+        .collect();
+
+    let mut frame = Vec::with_capacity(data.len());
+    let mut ranges = Vec::new();
+    let count = obus.len();
+    for (index, Obu { header, payload }) in obus.into_iter().enumerate() {
+        let start = frame.len();
+        let last = index == count - 1;
+
+        frame.extend_from_slice(header);
+        if last {
+            frame[start] &= !OBU_HAS_SIZE_FIELD;
+        } else {
+            frame[start] |= OBU_HAS_SIZE_FIELD;
+            av1::write_leb128(&mut frame, payload.len());
+        }
+        push_range(&mut ranges, start..frame.len());
+
+        frame.extend_from_slice(payload);
+    }
+
+    (frame, ranges)
+}
+
+/// Appends a range, merging it with the previous one if they touch.
+fn push_range(ranges: &mut Vec<Range<usize>>, range: Range<usize>) {
+    if range.is_empty() {
+        return;
+    }
+    match ranges.last_mut() {
+        Some(last) if last.end == range.start => last.end = range.end,
+        _ => ranges.push(range),
+    }
+}
@@ -0,0 +1,271 @@
+//! Frame encryption of Discord's audio and video end-to-end encryption (DAVE) protocol.
+//!
+//! Encoded frames are encrypted with AES-128-GCM before packetization, the transport
+//! encryption of [`crate::crypto`] is applied on top of that. A trailer appended to each
+//! frame carries the truncated tag, the nonce and the ranges left unencrypted.
+mod codec;
+
+pub use codec::Codec;
+
+use std::fmt;
+use std::ops::Range;
+
+use aes_gcm::aead::{AeadInPlace, KeyInit};
+use aes_gcm::{Aes128Gcm, Nonce};
+
+use crate::packetizer::av1::{read_leb128, write_leb128};
+
+/// Size of the AES-128 keys handed out by the sender's key ratchet.
+pub const KEY_SIZE: usize = 16;
+
+/// Size of the truncated AES-GCM authentication tag.
+const TAG_SIZE: usize = 8;
+/// Marks the end of a frame carrying a DAVE trailer.
+const MAGIC_MARKER: [u8; 2] = [0xFA, 0xFA];
+/// Trailer bytes besides the nonce and the ranges: tag, size byte and magic marker.
+const TRAILER_FIXED_SIZE: usize = TAG_SIZE + 1 + MAGIC_MARKER.len();
+
+/// Offset of the 4B truncated nonce within the 12B AES-GCM nonce.
+const TRUNCATED_NONCE_OFFSET: usize = 8;
+/// The key ratchet generation is carried in the top byte of the truncated nonce.
+const GENERATION_SHIFT: u32 = 24;
+const MAX_NONCES_PER_GENERATION: u32 = 1 << GENERATION_SHIFT;
+
+/// Number of nonces tried before giving up on producing a packetizable frame.
+const MAX_ENCRYPTION_ATTEMPTS: usize = 10;
+
+/// Reasons a frame could not be encrypted.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EncryptError {
+    /// All nonces of the key ratchet generation were used, a new key is needed.
+    NonceExhausted,
+    /// No nonce produced a frame which survives packetization.
+    Unpacketizable,
+    /// The frame has too many unencrypted ranges to describe in the trailer.
+    TooManyRanges,
+}
+
+impl fmt::Display for EncryptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncryptError::NonceExhausted => write!(f, "all nonces of the key generation were used"),
+            EncryptError::Unpacketizable => write!(f, "encrypted frame cannot be packetized"),
+            EncryptError::TooManyRanges => write!(f, "too many unencrypted ranges for the trailer"),
+        }
+    }
+}
+
+impl std::error::Error for EncryptError {}
+
+/// Reasons a frame could not be decrypted.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DecryptError {
+    /// The frame does not end with the magic marker.
+    MissingTrailer,
+    /// The trailer sizes or ranges do not fit the frame.
+    MalformedTrailer,
+    /// The authentication tag does not match the frame contents.
+    BadTag,
+}
+
+impl fmt::Display for DecryptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecryptError::MissingTrailer => write!(f, "frame has no DAVE trailer"),
+            DecryptError::MalformedTrailer => write!(f, "malformed DAVE trailer"),
+            DecryptError::BadTag => write!(f, "authentication tag mismatch"),
+        }
+    }
+}
+
+impl std::error::Error for DecryptError {}
+
+/// Encrypts the frames of one sender with the key of a single ratchet generation.
+///
+/// The key schedule is wiped when the encryptor is dropped.
+pub struct FrameEncryptor {
+    cipher: Aes128Gcm,
+    generation: u8,
+    /// Nonces used so far within the generation.
+    counter: u32,
+}
+
+impl fmt::Debug for FrameEncryptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FrameEncryptor")
+            .field("generation", &self.generation)
+            .field("counter", &self.counter)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FrameEncryptor {
+    pub fn new(key: &[u8; KEY_SIZE], generation: u8) -> Self {
+        Self {
+            cipher: Aes128Gcm::new(key.into()),
+            generation,
+            counter: 0,
+        }
+    }
+
+    /// Encrypts an encoded frame, returning it with the DAVE trailer appended.
+    pub fn encrypt(&mut self, codec: Codec, frame: &[u8]) -> Result<Vec<u8>, EncryptError> {
+        let (plain, unencrypted) = codec.unencrypted_ranges(frame);
+        let encrypted_ranges = complement(&unencrypted, plain.len());
+
+        for _ in 0..MAX_ENCRYPTION_ATTEMPTS {
+            let nonce = self.next_nonce()?;
+            let encrypted = self.encrypt_with_nonce(&plain, &unencrypted, &encrypted_ranges, nonce)?;
+            if codec.is_packetizable(&plain, &encrypted, &encrypted_ranges) {
+                return Ok(encrypted);
+            }
+        }
+
+        Err(EncryptError::Unpacketizable)
+    }
+
+    fn next_nonce(&mut self) -> Result<u32, EncryptError> {
+        if self.counter >= MAX_NONCES_PER_GENERATION {
+            return Err(EncryptError::NonceExhausted);
+        }
+
+        let nonce = (u32::from(self.generation) << GENERATION_SHIFT) | self.counter;
+        self.counter += 1;
+        Ok(nonce)
+    }
+
+    fn encrypt_with_nonce(
+        &self,
+        plain: &[u8],
+        unencrypted: &[Range<usize>],
+        encrypted_ranges: &[Range<usize>],
+        truncated_nonce: u32,
+    ) -> Result<Vec<u8>, EncryptError> {
+        let aad: Vec<u8> = unencrypted.iter().flat_map(|range| &plain[range.clone()]).copied().collect();
+        let mut ciphertext: Vec<u8> = encrypted_ranges.iter().flat_map(|range| &plain[range.clone()]).copied().collect();
+
+        let tag = self
+            .cipher
+            .encrypt_in_place_detached(&full_nonce(truncated_nonce), &aad, &mut ciphertext)
+            .expect("Frame too large for AES-GCM");
+
+        let mut frame = plain.to_vec();
+        let mut ciphertext = &ciphertext[..];
+        for range in encrypted_ranges {
+            let (chunk, rest) = ciphertext.split_at(range.len());
+            frame[range.clone()].copy_from_slice(chunk);
+            ciphertext = rest;
+        }
+
+        let trailer_start = frame.len();
+        frame.extend_from_slice(&tag[..TAG_SIZE]);
+        write_leb128(&mut frame, truncated_nonce as usize);
+        for range in unencrypted {
+            write_leb128(&mut frame, range.start);
+            write_leb128(&mut frame, range.len());
+        }
+        let trailer_size = frame.len() - trailer_start + 1 + MAGIC_MARKER.len();
+        let trailer_size = u8::try_from(trailer_size).map_err(|_| EncryptError::TooManyRanges)?;
+        frame.push(trailer_size);
+        frame.extend_from_slice(&MAGIC_MARKER);
+
+        Ok(frame)
+    }
+}
+
+/// Decrypts a frame produced by [`FrameEncryptor::encrypt`] with the key of the
+/// generation it was encrypted with.
+///
+/// Returns the frame as it was passed to encryption, apart from codec specific
+/// rewriting for H.264 and AV1.
+pub fn decrypt_frame(key: &[u8; KEY_SIZE], frame: &[u8]) -> Result<Vec<u8>, DecryptError> {
+    if !frame.ends_with(&MAGIC_MARKER) {
+        return Err(DecryptError::MissingTrailer);
+    }
+
+    let size_index = frame.len().checked_sub(MAGIC_MARKER.len() + 1).ok_or(DecryptError::MalformedTrailer)?;
+    let trailer_size = usize::from(frame[size_index]);
+    if trailer_size < TRAILER_FIXED_SIZE || trailer_size > frame.len() {
+        return Err(DecryptError::MalformedTrailer);
+    }
+
+    let body_len = frame.len() - trailer_size;
+    let (body, trailer) = frame.split_at(body_len);
+    let (tag, mut fields) = trailer[..trailer_size - 1 - MAGIC_MARKER.len()].split_at(TAG_SIZE);
+
+    let truncated_nonce = u32::try_from(take_leb128(&mut fields)?).map_err(|_| DecryptError::MalformedTrailer)?;
+
+    let mut unencrypted: Vec<Range<usize>> = Vec::new();
+    while !fields.is_empty() {
+        let start = take_leb128(&mut fields)?;
+        let len = take_leb128(&mut fields)?;
+        let end = start.checked_add(len).ok_or(DecryptError::MalformedTrailer)?;
+        if end > body_len || matches!(unencrypted.last(), Some(last) if start < last.end) {
+            return Err(DecryptError::MalformedTrailer);
+        }
+        unencrypted.push(start..end);
+    }
+    let encrypted_ranges = complement(&unencrypted, body_len);
+
+    let aad: Vec<u8> = unencrypted.iter().flat_map(|range| &body[range.clone()]).copied().collect();
+    let ciphertext: Vec<u8> = encrypted_ranges.iter().flat_map(|range| &body[range.clone()]).copied().collect();
+
+    // GCM only offers verification of full tags. Its keystream is applied symmetrically,
+    // so encrypting the ciphertext yields the plaintext, and encrypting that again
+    // yields the tag of the original ciphertext.
+    let cipher = Aes128Gcm::new(key.into());
+    let nonce = full_nonce(truncated_nonce);
+    let mut plaintext = ciphertext.clone();
+    cipher
+        .encrypt_in_place_detached(&nonce, &aad, &mut plaintext)
+        .map_err(|_| DecryptError::MalformedTrailer)?;
+    let mut reencrypted = plaintext.clone();
+    let expected_tag = cipher
+        .encrypt_in_place_detached(&nonce, &aad, &mut reencrypted)
+        .map_err(|_| DecryptError::MalformedTrailer)?;
+
+    let difference = expected_tag[..TAG_SIZE].iter().zip(tag).fold(0, |acc, (a, b)| acc | (a ^ b));
+    if difference != 0 {
+        return Err(DecryptError::BadTag);
+    }
+
+    let mut frame = body.to_vec();
+    let mut plaintext = &plaintext[..];
+    for range in encrypted_ranges {
+        let (chunk, rest) = plaintext.split_at(range.len());
+        frame[range].copy_from_slice(chunk);
+        plaintext = rest;
+    }
+
+    Ok(frame)
+}
+
+/// Expands a truncated nonce to the full AES-GCM nonce.
+fn full_nonce(truncated_nonce: u32) -> Nonce<aes_gcm::aead::consts::U12> {
+    let mut nonce = Nonce::default();
+    nonce[TRUNCATED_NONCE_OFFSET..].copy_from_slice(&truncated_nonce.to_le_bytes());
+    nonce
+}
+
+/// Returns the ranges of `0..len` not covered by the sorted, non-overlapping `ranges`.
+fn complement(ranges: &[Range<usize>], len: usize) -> Vec<Range<usize>> {
+    let mut gaps = Vec::new();
+    let mut position = 0;
+    for range in ranges {
+        if range.start > position {
+            gaps.push(position..range.start);
+        }
+        position = range.end;
+    }
+    if position < len {
+        gaps.push(position..len);
+    }
+    gaps
+}
+
+/// Reads a trailer field and advances past it.
+fn take_leb128(fields: &mut &[u8]) -> Result<usize, DecryptError> {
+    let (value, size) = read_leb128(fields).ok_or(DecryptError::MalformedTrailer)?;
+    *fields = &fields[size..];
+    Ok(value)
+}
@@ -1,17 +1,31 @@
-use std::net::UdpSocket;
+use std::borrow::Cow;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
 use std::sync::atomic::{AtomicU16, Ordering};
-use discortp::{Packet};
-use gst::{Caps, debug, error, FlowError, Fraction, glib, Pad, PadTemplate};
+use std::time::Duration;
+use discortp::{MutablePacket, Packet};
+use discortp::rtp::{MutableRtpPacket, RtpPacket, RtpType};
+use gio::prelude::*;
+use gst::{Caps, debug, error, FlowError, glib, Pad, PadTemplate, trace, warning};
 use gst::glib::{ParamSpec, Value};
 use gst::prelude::*;
 use gst::subclass::prelude::*;
 use once_cell::sync::Lazy;
 use parking_lot::Mutex;
-use xsalsa20poly1305::{KeyInit, TAG_SIZE};
-use xsalsa20poly1305::{Key, KEY_SIZE, XSalsa20Poly1305 as Cipher};
-
-use crate::constants::{RTP_AV1_PROFILE_TYPE, RTP_H264_PROFILE_TYPE, RTP_VERSION, RTP_VP8_PROFILE_TYPE, RTP_VP9_PROFILE_TYPE};
-use crate::crypto::{CryptoMode, CryptoState};
+use zeroize::Zeroizing;
+
+use crate::constants::{DEFAULT_MTU, MAX_MTU, MIN_MTU, OPUS_CLOCK_RATE, RTP_VERSION, VIDEO_CLOCK_RATE};
+use crate::crypto::{self, Cipher, CryptoMode, CryptoState};
+use crate::dave::{self, Codec, FrameEncryptor};
+use crate::discovery;
+#[cfg(debug_assertions)]
+use crate::crypto::NonceReuseDetector;
+use crate::packetizer::{self, FrameInfo, Packetizer, PacketizerConfig};
+use crate::keepalive::{DEAD_PATH_MISSED_KEEPALIVES, DEFAULT_KEEPALIVE_INTERVAL_MS};
+use crate::receiver::{Datagram, Event, ReceiveThread};
 
 pub static CAT: Lazy<gst::DebugCategory> = Lazy::new(|| {
     gst::DebugCategory::new(
@@ -21,121 +35,727 @@ pub static CAT: Lazy<gst::DebugCategory> = Lazy::new(|| {
     )
 });
 
+/// Which of the two RTP streams a sink pad feeds.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Media {
+    Video,
+    Audio,
+}
+
+/// How buffers arriving on a sink pad are turned into RTP packets.
+enum Payloader {
+    /// Encoded frames are split into payloads by one of our packetizers.
+    Packetize(Box<dyn Packetizer>),
+    /// Buffers already are RTP packets produced by an upstream payloader.
+    Passthrough,
+}
+
+/// RTP stream fed by one of the sink pads.
+struct Stream {
+    ssrc: u32,
+    /// Rate of the RTP timestamp clock in Hz.
+    clock_rate: u64,
+    /// RTP timestamp corresponding to running time zero, chosen randomly.
+    timestamp_base: u32,
+    last_timestamp: u32,
+    segment: gst::FormattedSegment<gst::ClockTime>,
+    payloader: Option<(RtpType, Payloader)>,
+    /// Whether every buffer holds a complete frame, otherwise frames end at
+    /// buffers flagged with `MARKER`.
+    frame_aligned: bool,
+    /// Whether the last buffer sent left a frame unfinished.
+    mid_frame: bool,
+}
+
+impl Stream {
+    fn new(ssrc: u32, clock_rate: u64) -> Self {
+        let timestamp_base = rand::random();
+
+        Self {
+            ssrc,
+            clock_rate,
+            timestamp_base,
+            last_timestamp: timestamp_base,
+            segment: gst::FormattedSegment::new(),
+            payloader: None,
+            frame_aligned: true,
+            mid_frame: false,
+        }
+    }
+
+    /// Returns the RTP timestamp of a buffer from its running time.
+    ///
+    /// Buffers continuing a frame share the timestamp of its first buffer. Others
+    /// without a PTS are stamped with `now`, the current running time of the
+    /// element, or share the previous timestamp if the element has no clock.
+    fn timestamp(&mut self, buffer: &gst::BufferRef, now: Option<gst::ClockTime>) -> u32 {
+        if self.mid_frame {
+            return self.last_timestamp;
+        }
+
+        let ticks = self
+            .segment
+            .to_running_time(buffer.pts())
+            .or(now)
+            .and_then(|running_time| running_time.nseconds().mul_div_floor(self.clock_rate, gst::ClockTime::SECOND.nseconds()));
+
+        if let Some(ticks) = ticks {
+            self.last_timestamp = self.timestamp_base.wrapping_add(ticks as u32);
+        }
+
+        self.last_timestamp
+    }
+
+    /// Whether `buffer` holds the last data of a frame.
+    fn ends_frame(&self, buffer: &gst::BufferRef) -> bool {
+        self.frame_aligned || buffer.flags().contains(gst::BufferFlags::MARKER)
+    }
+}
+
 struct State {
     crypto_state: CryptoState,
     cipher: Cipher,
+    /// Crypto settings changed while streaming, switched to at the next frame boundary.
+    pending_crypto: Option<(CryptoState, Cipher)>,
+    /// End-to-end encryption of frames, applied before the transport encryption.
+    dave: Option<FrameEncryptor>,
+    #[cfg(debug_assertions)]
+    nonces: NonceReuseDetector,
     udp_socket: UdpSocket,
-    video_ssrc: u32,
-    audio_ssrc: u32
+    /// Address of `udp_socket` as seen by the server, found by IP discovery.
+    ///
+    /// Unknown for sockets provided by the application, which did IP discovery itself.
+    external_address: Option<SocketAddr>,
+    /// Handles datagrams from the server until the state is dropped.
+    receiver: ReceiveThread,
+    /// Interval `receiver` sends keepalives at.
+    keepalive_interval: Option<Duration>,
+    /// Options of the application's socket to put back, dropped after `receiver`
+    /// has stopped using them.
+    _provided_socket_options: Option<SocketOptions>,
+    /// Largest datagram sent to the server.
+    mtu: usize,
+    video: Stream,
+    audio: Stream,
 }
 
 impl State {
-    fn from_props(props: &Props) -> Result<Self, gst::ErrorMessage> {
-        let crypto_state = CryptoState::from(serde_plain::from_str::<CryptoMode>(props.crypto_mode.as_str()).map_err(|e| {
+    fn from_props(props: &Props, element: &super::DiscordStreamer) -> Result<Self, gst::ErrorMessage> {
+        let (crypto_state, cipher) = Self::crypto_from_props(props)?;
+        let dave = Self::dave_from_props(props)?;
+
+        let (udp_socket, server, provided_socket_options) = Self::socket_from_props(props)?;
+
+        let Some(video_ssrc) = props.video_ssrc else {
+            return Err(gst::error_msg!(
+                gst::ResourceError::NotFound,
+                ["No video SSRC provided"]
+            ));
+        };
+
+        let Some(audio_ssrc) = props.audio_ssrc else {
+            return Err(gst::error_msg!(
+                gst::ResourceError::NotFound,
+                ["No audio SSRC provided"]
+            ));
+        };
+
+        // Asking again would only delay streaming and send another request over the
+        // application's socket.
+        let external_address = if provided_socket_options.is_some() {
+            None
+        } else {
+            Some(discovery::discover(&udp_socket, audio_ssrc).map_err(|err| {
+                gst::error_msg!(
+                    gst::ResourceError::Read,
+                    ["IP discovery with {} failed: {}", server, err]
+                )
+            })?)
+        };
+
+        let keepalive_interval = Self::keepalive_interval(props);
+        let receiver = Self::spawn_receiver(&udp_socket, audio_ssrc, keepalive_interval, element)?;
+
+        Ok(Self {
+            crypto_state,
+            cipher,
+            pending_crypto: None,
+            dave,
+            #[cfg(debug_assertions)]
+            nonces: NonceReuseDetector::default(),
+            udp_socket,
+            external_address,
+            receiver,
+            keepalive_interval,
+            _provided_socket_options: provided_socket_options,
+            mtu: props.mtu as usize,
+            video: Stream::new(video_ssrc, VIDEO_CLOCK_RATE),
+            audio: Stream::new(audio_ssrc, OPUS_CLOCK_RATE),
+        })
+    }
+
+    fn keepalive_interval(props: &Props) -> Option<Duration> {
+        (props.keepalive_interval > 0).then(|| Duration::from_millis(props.keepalive_interval.into()))
+    }
+
+    fn spawn_receiver(
+        udp_socket: &UdpSocket,
+        ssrc: u32,
+        keepalive_interval: Option<Duration>,
+        element: &super::DiscordStreamer,
+    ) -> Result<ReceiveThread, gst::ErrorMessage> {
+        let element = element.downgrade();
+        ReceiveThread::spawn(udp_socket, ssrc, keepalive_interval, move |event| {
+            if let Some(element) = element.upgrade() {
+                element.imp().handle_event(event);
+            }
+        }).map_err(|err| {
             gst::error_msg!(
                 gst::ResourceError::Failed,
-                ["Failed to parse crypto mode: {}", e]
+                ["Failed to start receiving from the UDP socket: {}", err]
             )
-        })?);
+        })
+    }
+
+    /// Connects the socket provided by the application, or a new one bound to the
+    /// bind address and port, to the server.
+    ///
+    /// The options of a provided socket are returned as well, to be restored once
+    /// the element is done with it.
+    fn socket_from_props(props: &Props) -> Result<(UdpSocket, SocketAddr, Option<SocketOptions>), gst::ErrorMessage> {
+        if let Some(ProvidedSocket(socket)) = &props.socket {
+            return Self::adopt_socket(props, udp_socket_from_gio(socket));
+        }
+
+        #[cfg(unix)]
+        if props.socket_fd >= 0 {
+            return Self::adopt_socket(props, udp_socket_from_fd(props.socket_fd));
+        }
 
-        let Some(crypto_key) = &props.crypto_key else {
+        let Some(address) = &props.address else {
             return Err(gst::error_msg!(
                 gst::ResourceError::NotFound,
-                ["No crypto key provided"]
+                ["No address provided"]
             ));
         };
 
-        if crypto_key.len() != KEY_SIZE {
+        let bind_ip = match &props.bind_address {
+            Some(bind_address) => Some(bind_address.parse::<IpAddr>().map_err(|_| {
+                gst::error_msg!(
+                    gst::ResourceError::Settings,
+                    ["Bind address {} is no IP address", bind_address]
+                )
+            })?),
+            None => None,
+        };
+
+        let server = Self::resolve_server(address, bind_ip)?;
+
+        let bind_ip = bind_ip.unwrap_or(match server {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        });
+        let local = SocketAddr::new(bind_ip, props.bind_port as u16);
+
+        let udp_socket = UdpSocket::bind(local).map_err(|error| {
+            gst::error_msg!(
+                gst::ResourceError::Failed,
+                ["Failed to bind UDP socket to {}: {}", local, error]
+            )
+        })?;
+
+        if let Err(error) = udp_socket.connect(server) {
             return Err(gst::error_msg!(
                 gst::ResourceError::Failed,
-                ["Crypto key must be {} bytes long", KEY_SIZE]
+                ["Failed to connect UDP socket to {} ({}): {}", address, server, error]
             ));
+        };
+
+        debug!(CAT, "Sending from {} to {} ({})", local, address, server);
+        Ok((udp_socket, server, None))
+    }
+
+    /// Sends over the application's socket, connecting it to `address` if set and
+    /// otherwise keeping the server it is connected to.
+    fn adopt_socket(
+        props: &Props,
+        udp_socket: io::Result<UdpSocket>,
+    ) -> Result<(UdpSocket, SocketAddr, Option<SocketOptions>), gst::ErrorMessage> {
+        let udp_socket = udp_socket.map_err(|error| {
+            gst::error_msg!(
+                gst::ResourceError::OpenReadWrite,
+                ["Failed to use the provided socket: {}", error]
+            )
+        })?;
+
+        let local = udp_socket.local_addr().map_err(|error| {
+            gst::error_msg!(
+                gst::ResourceError::Settings,
+                ["The provided socket is not bound: {}", error]
+            )
+        })?;
+
+        if props.bind_address.is_some() || props.bind_port != 0 {
+            warning!(CAT, "Ignoring bind-address and bind-port, the provided socket is bound to {}", local);
         }
 
-        let mut key = [0u8; KEY_SIZE];
-        key.copy_from_slice(crypto_key);
-        let key = Key::from(key);
+        let server = match &props.address {
+            Some(address) => {
+                let server = Self::resolve_server(address, Some(local.ip()))?;
+                if let Err(error) = udp_socket.connect(server) {
+                    return Err(gst::error_msg!(
+                        gst::ResourceError::Failed,
+                        ["Failed to connect the provided socket to {} ({}): {}", address, server, error]
+                    ));
+                }
+                server
+            }
+            None => udp_socket.peer_addr().map_err(|_| {
+                gst::error_msg!(
+                    gst::ResourceError::NotFound,
+                    ["No address provided and the provided socket is not connected"]
+                )
+            })?,
+        };
 
-        let cipher = Cipher::new(&key);
+        // The duplicate shares the blocking mode and read timeout with the
+        // application's socket, which get their previous values back on teardown.
+        let options = SocketOptions::save(&udp_socket).map_err(|error| {
+            gst::error_msg!(
+                gst::ResourceError::Failed,
+                ["Failed to read the options of the provided socket: {}", error]
+            )
+        })?;
 
-        let Some(address) = &props.address else {
+        // GIO puts its sockets into non-blocking mode, IP discovery and the receive
+        // thread rely on read timeouts instead.
+        udp_socket.set_nonblocking(false).map_err(|error| {
+            gst::error_msg!(
+                gst::ResourceError::Failed,
+                ["Failed to make the provided socket blocking: {}", error]
+            )
+        })?;
+
+        debug!(CAT, "Sending from provided socket {} to {}", local, server);
+        Ok((udp_socket, server, Some(options)))
+    }
+
+    /// Resolves `address` to a server of the IP family of `bind_ip`, or of any
+    /// family if unset.
+    fn resolve_server(address: &str, bind_ip: Option<IpAddr>) -> Result<SocketAddr, gst::ErrorMessage> {
+        let candidates: Vec<SocketAddr> = address.to_socket_addrs().map_err(|error| {
+            gst::error_msg!(
+                gst::ResourceError::NotFound,
+                ["Failed to resolve {}: {}", address, error]
+            )
+        })?.collect();
+
+        let server = match bind_ip {
+            None => candidates.first().copied(),
+            Some(bind_ip) => candidates.iter().copied().find(|candidate| candidate.is_ipv4() == bind_ip.is_ipv4()).or_else(|| {
+                // Dual-stack sockets bound to any IPv6 address reach IPv4 servers
+                // through mapped addresses.
+                match bind_ip {
+                    IpAddr::V6(ip) if ip.is_unspecified() => candidates.iter().find_map(|candidate| match candidate {
+                        SocketAddr::V4(v4) => Some(SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port())),
+                        SocketAddr::V6(_) => None,
+                    }),
+                    _ => None,
+                }
+            }),
+        };
+
+        server.ok_or_else(|| match bind_ip {
+            Some(bind_ip) => gst::error_msg!(
+                gst::ResourceError::NotFound,
+                ["{} has no address of the IP family of bind address {}", address, bind_ip]
+            ),
+            None => gst::error_msg!(
+                gst::ResourceError::NotFound,
+                ["{} did not resolve to any address", address]
+            ),
+        })
+    }
+
+    fn crypto_from_props(props: &Props) -> Result<(CryptoState, Cipher), gst::ErrorMessage> {
+        Self::crypto(props.crypto_mode, props.crypto_key.as_deref().map(Vec::as_slice))
+    }
+
+    fn crypto(crypto_mode: CryptoMode, crypto_key: Option<&[u8]>) -> Result<(CryptoState, Cipher), gst::ErrorMessage> {
+        let crypto_state = CryptoState::from(crypto_mode);
+
+        let Some(crypto_key) = crypto_key else {
             return Err(gst::error_msg!(
                 gst::ResourceError::NotFound,
-                ["No address provided"]
+                ["No crypto key provided"]
             ));
         };
 
-        let Ok(udp_socket) = UdpSocket::bind("0.0.0.0:0") else {
+        if let Err(err) = crypto::validate_key(crypto_key) {
             return Err(gst::error_msg!(
                 gst::ResourceError::Failed,
-                ["Failed to bind UDP socket"]
+                ["Invalid crypto key: {}", err]
             ));
+        }
+
+        let cipher = Cipher::new(crypto_state.kind(), crypto_key).map_err(|_| {
+            gst::error_msg!(
+                gst::ResourceError::Failed,
+                ["Failed to initialize the cipher"]
+            )
+        })?;
+
+        Ok((crypto_state, cipher))
+    }
+
+    fn dave_from_props(props: &Props) -> Result<Option<FrameEncryptor>, gst::ErrorMessage> {
+        let Some(dave_key) = &props.dave_key else {
+            return Ok(None);
         };
 
-        if let Err(error) = udp_socket.connect(address.as_str()) {
+        let Ok(dave_key) = <&[u8; dave::KEY_SIZE]>::try_from(&dave_key[..]) else {
             return Err(gst::error_msg!(
                 gst::ResourceError::Failed,
-                ["Failed to connect UDP socket to {}: {}", address, error]
+                ["DAVE key must be {} bytes long", dave::KEY_SIZE]
             ));
         };
 
-        let Some(video_ssrc) = props.video_ssrc else {
+        Ok(Some(FrameEncryptor::new(dave_key, props.dave_key_generation as u8)))
+    }
+
+    /// Switches to crypto settings changed while streaming.
+    fn apply_pending_crypto(&mut self) {
+        let Some((crypto_state, cipher)) = self.pending_crypto.take() else {
+            return;
+        };
+
+        debug!(CAT, "Switching to new {:?} crypto settings", crypto_state.kind());
+        self.crypto_state = crypto_state;
+        self.cipher = cipher;
+        #[cfg(debug_assertions)]
+        {
+            self.nonces = NonceReuseDetector::default();
+        }
+    }
+
+    /// Largest RTP payload that keeps an encrypted packet without header
+    /// extensions within the MTU.
+    fn max_payload_size(&self) -> usize {
+        self.mtu - RtpPacket::minimum_packet_size() - self.crypto_state.kind().payload_overhead()
+    }
+
+    fn stream_mut(&mut self, media: Media) -> &mut Stream {
+        match media {
+            Media::Video => &mut self.video,
+            Media::Audio => &mut self.audio,
+        }
+    }
+
+    /// Size of an RTP packet once encrypted.
+    fn encrypted_len(&self, packet: &[u8]) -> usize {
+        packet.len() + self.crypto_state.kind().payload_overhead()
+    }
+
+    /// Encrypts an RTP packet and sends it to the server.
+    ///
+    /// The encrypted packet must not exceed the MTU.
+    fn send_packet(&mut self, packet: &[u8]) -> Result<(), gst::ErrorMessage> {
+        let Some(rtp) = RtpPacket::new(packet) else {
             return Err(gst::error_msg!(
-                gst::ResourceError::NotFound,
-                ["No video SSRC provided"]
+                gst::StreamError::Format,
+                ["Packet too short for an RTP header"]
             ));
         };
 
-        //		const blank = Buffer.alloc(74);
-        // 		blank.writeUInt16BE(1, 0);
-        // 		blank.writeUInt16BE(70, 2);
-        // 		blank.writeUInt32BE(this.voiceConnection.ssrc, 4);
-        // 		this.sendPacket(blank);
+        let mode = self.crypto_state.kind();
+        let payload = rtp.payload();
+        let header_len = packet.len() - payload.len();
+        let prefix_len = mode.payload_prefix_len();
 
-        let Some(audio_ssrc) = props.audio_ssrc else {
+        let encrypted_len = self.encrypted_len(packet);
+        let mut encrypted = vec![0u8; encrypted_len];
+        encrypted[..header_len].copy_from_slice(&packet[..header_len]);
+        encrypted[header_len + prefix_len..header_len + prefix_len + payload.len()].copy_from_slice(payload);
+
+        let mut rtp = MutableRtpPacket::new(&mut encrypted[..]).expect(
+            "FATAL: Too few bytes in self.packet for RTP header."
+        );
+
+        let final_payload_size = self.crypto_state.write_packet_nonce(&mut rtp, prefix_len + payload.len());
+
+        mode.encrypt_in_place(&mut rtp, &self.cipher, final_payload_size).map_err(|_| {
+            gst::error_msg!(
+                gst::StreamError::Failed,
+                ["Failed to encrypt packet"]
+            )
+        })?;
+
+        let encrypted = &encrypted[..header_len + final_payload_size];
+
+        #[cfg(debug_assertions)]
+        if !self.nonces.check(mode, encrypted) {
             return Err(gst::error_msg!(
-                gst::ResourceError::NotFound,
-                ["No audio SSRC provided"]
+                gst::StreamError::Failed,
+                ["Nonce reused, refusing to send packet"]
             ));
-        };
+        }
+
+        if let Err(error) = self.udp_socket.send(encrypted) {
+            warning!(CAT, "Failed to send RTP packet: {}", error);
+        }
+
+        Ok(())
+    }
+}
+
+/// Wraps `payload` in an unencrypted RTP header.
+fn rtp_packet(
+    ssrc: u32,
+    payload_type: RtpType,
+    sequence: u16,
+    timestamp: u32,
+    marker: bool,
+    payload: &[u8],
+) -> Vec<u8> {
+    let header_len = RtpPacket::minimum_packet_size();
+
+    let mut packet = vec![0u8; header_len + payload.len()];
+    let mut rtp = MutableRtpPacket::new(&mut packet[..]).expect(
+        "FATAL: Too few bytes in self.packet for RTP header."
+    );
+
+    rtp.set_version(RTP_VERSION);
+    rtp.set_ssrc(ssrc);
+    rtp.set_payload_type(payload_type);
+    rtp.set_sequence(sequence.into());
+    rtp.set_timestamp(timestamp.into());
+    rtp.set_marker(marker.into());
+    rtp.payload_mut().copy_from_slice(payload);
+
+    packet
+}
 
-        let mut blank = vec![0u8; 74];
-        blank[0..2].copy_from_slice(&1u16.to_be_bytes());
-        blank[2..4].copy_from_slice(&70u16.to_be_bytes());
-        blank[4..8].copy_from_slice(&audio_ssrc.to_be_bytes());
-        udp_socket.send(&blank).unwrap();
+/// Blocking mode and read timeout of a socket, restored when dropped.
+///
+/// Duplicated descriptors share these with the original socket.
+struct SocketOptions {
+    socket: UdpSocket,
+    nonblocking: bool,
+    read_timeout: Option<Duration>,
+}
 
+impl SocketOptions {
+    fn save(socket: &UdpSocket) -> io::Result<Self> {
         Ok(Self {
-            crypto_state,
-            cipher,
-            udp_socket,
-            video_ssrc,
-            audio_ssrc
+            socket: socket.try_clone()?,
+            nonblocking: is_nonblocking(socket)?,
+            read_timeout: socket.read_timeout()?,
         })
     }
 }
 
+impl Drop for SocketOptions {
+    fn drop(&mut self) {
+        if let Err(error) = self.socket.set_read_timeout(self.read_timeout) {
+            warning!(CAT, "Failed to restore the read timeout of the provided socket: {}", error);
+        }
+        if let Err(error) = self.socket.set_nonblocking(self.nonblocking) {
+            warning!(CAT, "Failed to restore the blocking mode of the provided socket: {}", error);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn is_nonblocking(socket: &UdpSocket) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    // SAFETY: F_GETFL only reads the flags of the open descriptor.
+    let flags = unsafe { libc::fcntl(socket.as_raw_fd(), libc::F_GETFL) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(flags & libc::O_NONBLOCK != 0)
+}
+
+/// Windows cannot query the blocking mode, GIO always makes its sockets non-blocking.
+#[cfg(windows)]
+fn is_nonblocking(_socket: &UdpSocket) -> io::Result<bool> {
+    Ok(true)
+}
+
+/// Duplicates the descriptor of `socket`, leaving the original to the application.
+#[cfg(unix)]
+fn udp_socket_from_gio(socket: &gio::Socket) -> io::Result<UdpSocket> {
+    use std::os::unix::io::{AsRawFd, BorrowedFd};
+
+    if socket.is_closed() {
+        return Err(io::Error::new(io::ErrorKind::NotConnected, "socket is closed"));
+    }
+    // SAFETY: the descriptor of an open socket stays valid while `socket` is borrowed.
+    let fd = unsafe { BorrowedFd::borrow_raw(socket.as_raw_fd()) };
+    Ok(UdpSocket::from(fd.try_clone_to_owned()?))
+}
+
+/// Duplicates the descriptor `fd` of the application, leaving the original to it.
+#[cfg(unix)]
+fn udp_socket_from_fd(fd: RawFd) -> io::Result<UdpSocket> {
+    use std::os::unix::io::BorrowedFd;
+
+    // SAFETY: the application keeps the descriptor open while it is set as socket-fd,
+    // a closed one fails to be duplicated.
+    let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+    Ok(UdpSocket::from(fd.try_clone_to_owned()?))
+}
+
+/// Duplicates the handle of `socket`, leaving the original to the application.
+#[cfg(windows)]
+fn udp_socket_from_gio(socket: &gio::Socket) -> io::Result<UdpSocket> {
+    use std::os::windows::io::{AsRawSocket, BorrowedSocket};
+
+    if socket.is_closed() {
+        return Err(io::Error::new(io::ErrorKind::NotConnected, "socket is closed"));
+    }
+    // SAFETY: the handle of an open socket stays valid while `socket` is borrowed.
+    let handle = unsafe { BorrowedSocket::borrow_raw(socket.as_raw_socket()) };
+    Ok(UdpSocket::from(handle.try_clone_to_owned()?))
+}
+
+/// Collects the codec information attached to `buffer` by the encoder.
+fn frame_info(buffer: &gst::BufferRef) -> FrameInfo {
+    #[allow(unused_mut)]
+    let mut info = FrameInfo {
+        keyframe: !buffer.flags().contains(gst::BufferFlags::DELTA_UNIT),
+        ..Default::default()
+    };
+
+    // vp8enc reports its temporal scalability decisions through a custom meta.
+    #[cfg(feature = "v1_20")]
+    if let Ok(meta) = gst::meta::CustomMeta::from_buffer(buffer, "GstVP8Meta") {
+        let s = meta.structure();
+        if s.get::<bool>("use-temporal-scaling").unwrap_or(false) {
+            info.temporal_layer = Some(crate::packetizer::TemporalLayer {
+                id: s.get::<u32>("layer-id").unwrap_or(0) as u8,
+                sync: s.get::<bool>("layer-sync").unwrap_or(false),
+                tl0_pic_idx: s.get::<u32>("tl0picidx").unwrap_or(0) as u8,
+            });
+        }
+    }
+
+    info
+}
+
+/// Copies a key out of a `glib::Bytes` property value into memory wiped on drop.
+fn secret_key(value: &Value) -> Option<Zeroizing<Vec<u8>>> {
+    value
+        .get::<Option<glib::Bytes>>()
+        .expect("type checked upstream")
+        .map(|bytes| Zeroizing::new(bytes.to_vec()))
+}
+
+/// A socket provided by the application.
+#[derive(Clone, Debug)]
+struct ProvidedSocket(gio::Socket);
+
+// SAFETY: GSocket is thread-safe, the bindings merely do not declare it.
+unsafe impl Send for ProvidedSocket {}
+unsafe impl Sync for ProvidedSocket {}
+
 struct Pads {
     video_sink: Pad,
     audio_sink: Option<Pad>,
 }
 
+#[derive(Clone)]
 struct Props {
-    crypto_key: Option<glib::Bytes>,
-    crypto_mode: glib::GString,
+    /// Secret keys are copied out of the property values so they can be wiped.
+    crypto_key: Option<Zeroizing<Vec<u8>>>,
+    crypto_mode: CryptoMode,
+    available_crypto_modes: Vec<String>,
+    /// Crypto properties were set after the state was created, applied once
+    /// streaming starts.
+    crypto_changed: bool,
     address: Option<glib::GString>,
+    bind_address: Option<glib::GString>,
+    bind_port: u32,
+    /// Socket of the application to send from instead of binding a new one.
+    ///
+    /// The receive thread reads from it as well, so datagrams arriving between
+    /// READY and NULL are consumed by the element. Its blocking mode and read
+    /// timeout are changed meanwhile and restored afterwards.
+    ///
+    /// The application did IP discovery over it already, so it is not repeated.
+    socket: Option<ProvidedSocket>,
+    /// Descriptor of the application's socket, used like `socket` if that is unset.
+    #[cfg(unix)]
+    socket_fd: RawFd,
+    close_socket: bool,
     video_ssrc: Option<u32>,
     audio_ssrc: Option<u32>,
+    vp9_flexible_mode: bool,
+    mtu: u32,
+    /// Milliseconds between keepalives, 0 to disable them.
+    keepalive_interval: u32,
+    dave_key: Option<Zeroizing<Vec<u8>>>,
+    dave_key_generation: u32,
+}
+
+impl fmt::Debug for Props {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("Props");
+        debug
+            .field("crypto_key", &self.crypto_key.as_ref().map(|_| Redacted))
+            .field("crypto_mode", &self.crypto_mode)
+            .field("available_crypto_modes", &self.available_crypto_modes)
+            .field("crypto_changed", &self.crypto_changed)
+            .field("address", &self.address)
+            .field("bind_address", &self.bind_address)
+            .field("bind_port", &self.bind_port)
+            .field("socket", &self.socket);
+        #[cfg(unix)]
+        debug.field("socket_fd", &self.socket_fd);
+        debug
+            .field("close_socket", &self.close_socket)
+            .field("video_ssrc", &self.video_ssrc)
+            .field("audio_ssrc", &self.audio_ssrc)
+            .field("vp9_flexible_mode", &self.vp9_flexible_mode)
+            .field("mtu", &self.mtu)
+            .field("keepalive_interval", &self.keepalive_interval)
+            .field("dave_key", &self.dave_key.as_ref().map(|_| Redacted))
+            .field("dave_key_generation", &self.dave_key_generation)
+            .finish()
+    }
+}
+
+/// Stands in for key material in `Debug` output.
+struct Redacted;
+
+impl fmt::Debug for Redacted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
 }
 
 impl Default for Props {
     fn default() -> Self {
         Self {
             crypto_key: None,
-            crypto_mode: serde_plain::to_string(&CryptoMode::Lite).unwrap().into(),
+            crypto_mode: CryptoMode::Lite,
+            available_crypto_modes: Vec::new(),
+            crypto_changed: false,
             address: None,
+            bind_address: None,
+            bind_port: 0,
+            socket: None,
+            #[cfg(unix)]
+            socket_fd: -1,
+            close_socket: true,
             video_ssrc: None,
             audio_ssrc: None,
+            vp9_flexible_mode: false,
+            mtu: DEFAULT_MTU as u32,
+            keepalive_interval: DEFAULT_KEEPALIVE_INTERVAL_MS,
+            dave_key: None,
+            dave_key_generation: 0,
         }
     }
 }
@@ -166,66 +786,327 @@ impl DiscordStreamer {
         sequence
     }
 
+    fn next_sequence(&self, media: Media) -> u16 {
+        match media {
+            Media::Video => self.get_video_sequence(),
+            Media::Audio => self.get_audio_sequence(),
+        }
+    }
+
     //https://github.com/serenity-rs/songbird/blob/22fe3f3d4e43db67f1cdb7c9574867539517fb51/src/driver/tasks/mixer.rs#L484
-    fn video_sink_chain(
+    fn sink_chain(
         &self,
-        pad: &Pad,
+        media: Media,
         buffer: gst::Buffer,
     ) -> Result<gst::FlowSuccess, FlowError> {
-        let mut packet = vec![0u8; buffer.size()];
+        let map = buffer.map_readable().map_err(|_| {
+            error!(CAT, imp: self, "Failed to map {:?} buffer readable", media);
+            FlowError::Error
+        })?;
 
-        let _ = buffer.copy_to_slice(0, &mut packet);
-        let mut rtp = discortp::rtp::MutableRtpPacket::new(&mut packet[..]).expect(
-            "FATAL: Too few bytes in self.packet for RTP header."
-        );
-
-        rtp.set_version(RTP_VERSION);
+        let now = self.obj().current_running_time();
 
         let mut state = self.state.lock();
         let state = state.as_mut().expect("State not initialized");
 
-        rtp.set_ssrc(state.video_ssrc);
+        // New keys only apply to whole frames so the receiver never sees a frame
+        // encrypted with two different keys.
+        if !state.video.mid_frame && !state.audio.mid_frame {
+            state.apply_pending_crypto();
+        }
+
+        let max_payload_size = state.max_payload_size();
+        let info = FrameInfo {
+            encrypted: state.dave.is_some(),
+            ..frame_info(&buffer)
+        };
 
-        let caps = pad.current_caps().expect("No caps on pad");
-        let caps = caps.structure(0).expect("No structure on caps");
+        // End-to-end encryption covers whole encoded frames, before packetization.
+        let frame = match state.dave.as_mut() {
+            Some(dave) => {
+                let stream = match media {
+                    Media::Video => &state.video,
+                    Media::Audio => &state.audio,
+                };
+
+                let codec = match &stream.payloader {
+                    Some((payload_type, Payloader::Packetize(_))) if stream.frame_aligned => Codec::for_payload_type(*payload_type),
+                    _ => None,
+                };
+                let Some(codec) = codec else {
+                    gst::element_imp_error!(self, gst::StreamError::Format, ["End-to-end encryption needs frame aligned encoded {:?}", media]);
+                    return Err(FlowError::NotSupported);
+                };
+
+                match dave.encrypt(codec, &map) {
+                    Ok(frame) => Cow::Owned(frame),
+                    Err(err) => {
+                        gst::element_imp_error!(self, gst::StreamError::Failed, ["Failed to encrypt {:?} frame: {}", media, err]);
+                        return Err(FlowError::Error);
+                    }
+                }
+            }
+            None => Cow::Borrowed(&map[..]),
+        };
 
-        let encoding_name = caps.name().as_str();
+        let stream = state.stream_mut(media);
+        let ssrc = stream.ssrc;
+        let timestamp = stream.timestamp(&buffer, now);
+        let ends_frame = stream.ends_frame(&buffer);
+        // The marker bit flags the last packet of each video frame.
+        let marks_frame = media == Media::Video && ends_frame;
 
-        let rtp_type = match encoding_name {
-            "video/x-av1" => RTP_AV1_PROFILE_TYPE,
-            "video/x-h264" => RTP_H264_PROFILE_TYPE,
-            "video/x-vp8" => RTP_VP8_PROFILE_TYPE,
-            "video/x-vp9" => RTP_VP9_PROFILE_TYPE,
-            _ => return Err(FlowError::NotSupported)
+        let Some((payload_type, payloader)) = stream.payloader.as_mut() else {
+            error!(CAT, imp: self, "No payloader negotiated for {:?}", media);
+            return Err(FlowError::NotNegotiated);
+        };
+        let payload_type = *payload_type;
+
+        let packets = match payloader {
+            Payloader::Packetize(packetizer) => {
+                let payloads = packetizer.packetize(&frame, &info, max_payload_size);
+                let last = payloads.len().saturating_sub(1);
+                payloads.iter().enumerate().map(|(index, payload)| {
+                    let marker = marks_frame && index == last;
+                    rtp_packet(ssrc, payload_type, self.next_sequence(media), timestamp, marker, payload)
+                }).collect()
+            }
+            Payloader::Passthrough => {
+                let mut packet = map.to_vec();
+                let rtp = MutableRtpPacket::new(&mut packet[..]).filter(|rtp| rtp.get_version() == RTP_VERSION);
+                let Some(mut rtp) = rtp else {
+                    gst::element_imp_error!(self, gst::StreamError::Format, ["Buffer is not an RTP packet"]);
+                    return Err(FlowError::Error);
+                };
+
+                // Upstream payloaders pick their own SSRC, payload type and sequence numbers.
+                rtp.set_ssrc(ssrc);
+                rtp.set_payload_type(payload_type);
+                rtp.set_sequence(self.next_sequence(media).into());
+                vec![packet]
+            }
         };
-        rtp.set_payload_type(rtp_type);
 
-        rtp.set_sequence(self.get_video_sequence().into());
+        for packet in &packets {
+            // Opus frames and packets from upstream payloaders may carry header extensions
+            // or simply be too large, they cannot be split any further.
+            let encrypted_len = state.encrypted_len(packet);
+            if encrypted_len > state.mtu {
+                gst::element_imp_warning!(
+                    self,
+                    gst::StreamError::Failed,
+                    ["Dropping {} byte {:?} packet exceeding the MTU of {} bytes", encrypted_len, media, state.mtu]
+                );
+                continue;
+            }
+
+            state.send_packet(packet).map_err(|err| {
+                self.post_error_message(err);
+                FlowError::Error
+            })?;
+        }
+        state.stream_mut(media).mid_frame = !ends_frame;
+
+        Ok(gst::FlowSuccess::Ok)
+    }
 
-        let fps = caps.get::<Fraction>("framerate").expect("No framerate on caps");
-        let fps = fps.numer() as u32 / fps.denom() as u32;
-        rtp.set_timestamp((90_000 / fps).into());
+    /// Switches to `crypto_mode` and `crypto_key` at the next frame boundary, or
+    /// when streaming starts.
+    ///
+    /// Both change together, so no packet is encrypted with the new mode and the
+    /// old key. Returns whether the settings were valid, invalid ones are ignored.
+    fn switch_crypto(&self, crypto_mode: CryptoMode, crypto_key: Option<Zeroizing<Vec<u8>>>) -> bool {
+        let crypto = match State::crypto(crypto_mode, crypto_key.as_deref().map(Vec::as_slice)) {
+            Ok(crypto) => crypto,
+            Err(err) => {
+                warning!(CAT, imp: self, "Keeping the current crypto settings: {:?}", err);
+                return false;
+            }
+        };
 
-        let payload_size = rtp.payload().len() - state.crypto_state.kind().payload_suffix_len();
+        let mut props = self.props.lock();
+        props.crypto_mode = crypto_mode;
+        props.crypto_key = crypto_key;
+        props.crypto_changed = false;
+        drop(props);
 
-        //let final_payload_size = state.crypto_state.write_packet_nonce(&mut rtp, TAG_SIZE + payload_size);
+        if let Some(state) = self.state.lock().as_mut() {
+            debug!(CAT, imp: self, "Switching to {:?} crypto settings at the next frame boundary", crypto.0.kind());
+            state.pending_crypto = Some(crypto);
+        }
+        true
+    }
 
-        let final_payload_size = state.crypto_state.write_packet_nonce(&mut rtp,  payload_size);
+    /// Switches end-to-end encryption to the current DAVE properties if the element
+    /// is already streaming.
+    fn update_dave(&self) {
+        let dave = State::dave_from_props(&self.props.lock());
 
-        state.crypto_state.kind().encrypt_in_place(&mut rtp, &state.cipher, final_payload_size).expect("Failed to encrypt packet");
+        let mut state = self.state.lock();
+        let Some(state) = state.as_mut() else {
+            return;
+        };
 
-        let _ = state.udp_socket.send(&rtp.packet()[..final_payload_size]);
+        match dave {
+            Ok(dave) => state.dave = dave,
+            Err(err) => {
+                warning!(CAT, imp: self, "Keeping the current end-to-end encryption settings: {:?}", err);
+            }
+        }
+    }
 
-        Ok(gst::FlowSuccess::Ok)
+    /// Drops the state, closing the socket once the receive thread has stopped.
+    fn stop(&self) {
+        // Joining the receive thread takes up to one poll interval, streaming threads
+        // must not wait for the lock meanwhile.
+        let state = self.state.lock().take();
+        drop(state);
     }
 
-    fn audio_sink_chain(
-        &self,
-        _pad: &Pad,
-        _buffer: gst::Buffer,
-    ) -> Result<gst::FlowSuccess, FlowError> {
-        error!(CAT, "Audio not supported yet");
-        Err(FlowError::NotSupported)
+    /// Closes the socket provided by the application if close-socket is set.
+    ///
+    /// Done on the way back to NULL, whether or not the element ever reached READY.
+    fn close_provided_socket(&self) {
+        let mut props = self.props.lock();
+        if !props.close_socket {
+            return;
+        }
+
+        if let Some(ProvidedSocket(socket)) = &props.socket {
+            debug!(CAT, imp: self, "Closing the provided socket");
+            if let Err(err) = socket.close() {
+                warning!(CAT, imp: self, "Failed to close the provided socket: {}", err);
+            }
+            return;
+        }
+
+        #[cfg(unix)]
+        if props.socket_fd >= 0 {
+            debug!(CAT, imp: self, "Closing the provided socket descriptor {}", props.socket_fd);
+            // SAFETY: the application hands the descriptor over by setting close-socket.
+            if unsafe { libc::close(props.socket_fd) } != 0 {
+                warning!(CAT, imp: self, "Failed to close the provided socket descriptor: {}", io::Error::last_os_error());
+            }
+            // The number may be reused by the next descriptor opened.
+            props.socket_fd = -1;
+        }
+    }
+
+    /// Passes what the receive thread saw on to whatever needs it.
+    ///
+    /// Called from the receive thread.
+    fn handle_event(&self, event: Event) {
+        match event {
+            Event::Received(Datagram::Discovery(address)) => {
+                debug!(CAT, imp: self, "Ignoring repeated IP discovery response with {}", address);
+            }
+            Event::Received(Datagram::Keepalive(counter)) => {
+                debug!(CAT, imp: self, "Ignoring echo of unknown keepalive {}", counter);
+            }
+            Event::Received(Datagram::Rtcp(rtcp)) => {
+                // Server reports are encrypted past the header, only log their type.
+                let packet = rtcp.packet();
+                trace!(CAT, imp: self, "Received {} byte RTCP packet of type {}", packet.len(), packet[1]);
+            }
+            Event::Received(Datagram::Rtp(rtp)) => {
+                // Media of other participants, the element only sends.
+                trace!(CAT, imp: self, "Dropping RTP packet of SSRC {}", rtp.get_ssrc());
+            }
+            Event::Received(Datagram::Unknown(packet)) => {
+                debug!(CAT, imp: self, "Dropping unknown {} byte datagram", packet.len());
+            }
+            Event::KeepaliveEchoed(round_trip) => {
+                trace!(CAT, imp: self, "Keepalive echoed after {:?}", round_trip);
+            }
+            Event::KeepaliveMissed(missed) => {
+                debug!(CAT, imp: self, "{} keepalives in a row were not echoed", missed);
+                if missed == DEAD_PATH_MISSED_KEEPALIVES {
+                    gst::element_imp_warning!(
+                        self,
+                        gst::ResourceError::Read,
+                        ["The server did not echo the last {} keepalives, the connection appears dead", missed]
+                    );
+                }
+            }
+            Event::Error(err) => {
+                warning!(CAT, imp: self, "Failed to receive from the UDP socket: {}", err);
+            }
+        }
+    }
+
+    /// Announces the address found by IP discovery on the bus.
+    fn post_external_address(&self, external_address: SocketAddr) {
+        debug!(CAT, imp: self, "External address is {}", external_address);
+
+        let obj = self.obj();
+        obj.notify("external-ip");
+        obj.notify("external-port");
+
+        let structure = gst::Structure::builder("discord-ip-discovery")
+            .field("ip", external_address.ip().to_string())
+            .field("port", u32::from(external_address.port()))
+            .build();
+        let _ = obj.post_message(gst::message::Element::builder(structure).src(&*obj).build());
+    }
+
+    fn sink_event(&self, media: Media, pad: &Pad, event: gst::Event) -> bool {
+        match event.view() {
+            gst::EventView::Caps(caps) => {
+                let Some(structure) = caps.caps().structure(0) else {
+                    return false;
+                };
+
+                let Some(payloader) = self.payloader_for_caps(structure) else {
+                    error!(CAT, imp: self, "Unsupported {:?} caps {}", media, structure);
+                    return false;
+                };
+
+                // Encoders output whole frames unless configured for a finer alignment.
+                let frame_aligned = match structure.get_optional::<&str>("alignment") {
+                    Ok(Some(alignment)) => matches!(alignment, "au" | "tu"),
+                    _ => true,
+                };
+
+                if let Some(state) = self.state.lock().as_mut() {
+                    let stream = state.stream_mut(media);
+                    stream.payloader = Some(payloader);
+                    stream.frame_aligned = frame_aligned;
+                }
+            }
+            gst::EventView::Segment(segment) => {
+                let Some(segment) = segment.segment().downcast_ref::<gst::ClockTime>() else {
+                    error!(CAT, imp: self, "Only time segments are supported");
+                    return false;
+                };
+
+                if let Some(state) = self.state.lock().as_mut() {
+                    state.stream_mut(media).segment = segment.clone();
+                }
+            }
+            _ => (),
+        }
+
+        Pad::event_default(pad, Some(&*self.obj()), event)
+    }
+
+    fn payloader_for_caps(&self, structure: &gst::StructureRef) -> Option<(RtpType, Payloader)> {
+        if structure.name() == "application/x-rtp" {
+            let encoding_name = structure.get::<&str>("encoding-name").ok()?;
+            let payload_type = packetizer::payload_type_for_encoding_name(encoding_name)?;
+            return Some((payload_type, Payloader::Passthrough));
+        }
+
+        let frame_size = match (structure.get::<i32>("width"), structure.get::<i32>("height")) {
+            (Ok(width), Ok(height)) => u16::try_from(width).ok().zip(u16::try_from(height).ok()),
+            _ => None,
+        };
+        let config = PacketizerConfig {
+            vp9_flexible_mode: self.props.lock().vp9_flexible_mode,
+            frame_size,
+        };
+
+        packetizer::for_caps_name(structure.name(), &config)
+            .map(|(payload_type, packetizer)| (payload_type, Payloader::Packetize(packetizer)))
     }
 }
 
@@ -237,11 +1118,17 @@ impl ObjectSubclass for DiscordStreamer {
 
     fn with_class(klass: &Self::Class) -> Self {
         let templ = klass.pad_template("video_sink").unwrap();
-        let video_sink = Pad::builder_with_template(&templ, Some("video_sink")).chain_function(|pad, parent, buffer| {
+        let video_sink = Pad::builder_with_template(&templ, Some("video_sink")).chain_function(|_pad, parent, buffer| {
             DiscordStreamer::catch_panic_pad_function(
                 parent,
                 || Err(FlowError::Error),
-                |s| s.video_sink_chain(pad, buffer),
+                |s| s.sink_chain(Media::Video, buffer),
+            )
+        }).event_function(|pad, parent, event| {
+            DiscordStreamer::catch_panic_pad_function(
+                parent,
+                || false,
+                |s| s.sink_event(Media::Video, pad, event),
             )
         }).build();
 
@@ -259,21 +1146,63 @@ impl ObjectSubclass for DiscordStreamer {
 }
 
 impl ObjectImpl for DiscordStreamer {
+    fn signals() -> &'static [glib::subclass::Signal] {
+        static SIGNALS: Lazy<Vec<glib::subclass::Signal>> = Lazy::new(|| {
+            vec![
+                // Switches crypto-mode and crypto-key at once at the next frame boundary,
+                // returning whether the key fits the mode.
+                glib::subclass::Signal::builder("switch-crypto")
+                    .param_types([CryptoMode::static_type(), glib::Bytes::static_type()])
+                    .return_type::<bool>()
+                    .action()
+                    .class_handler(|_, args| {
+                        let element = args[0].get::<super::DiscordStreamer>().expect("signal arg");
+                        let crypto_mode = args[1].get::<CryptoMode>().expect("signal arg");
+                        Some(element.imp().switch_crypto(crypto_mode, secret_key(&args[2])).to_value())
+                    })
+                    .build(),
+            ]
+        });
+
+        SIGNALS.as_ref()
+    }
+
     fn properties() -> &'static [ParamSpec] {
         static PROPERTIES: Lazy<Vec<ParamSpec>> = Lazy::new(|| {
-            vec![
-                glib::ParamSpecBoxed::builder::<glib::Bytes>("crypto-key").nick("Crypto Key").blurb("The key used to encrypt the stream").build(),
-                glib::ParamSpecString::builder("crypto-mode").nick("Crypto Mode").blurb(
-                    format!(
-                        "The mode used to encrypt the stream. Available modes: {}, {}, {}",
-                        serde_plain::to_string(&CryptoMode::Normal).unwrap(),
-                        serde_plain::to_string(&CryptoMode::Lite).unwrap(),
-                        serde_plain::to_string(&CryptoMode::Suffix).unwrap()).as_str()
-                ).write_only().build(),
-                glib::ParamSpecString::builder("address").nick("Address").blurb("The address to stream to").build(),
+            #[allow(unused_mut)]
+            let mut properties = vec![
+                glib::ParamSpecBoxed::builder::<glib::Bytes>("crypto-key").nick("Crypto Key").blurb("The key used to encrypt the stream, switch-crypto changes it together with the mode while streaming").write_only().mutable_ready().build(),
+                glib::ParamSpecString::builder("crypto-key-string").nick("Crypto Key String").blurb(
+                    "The key used to encrypt the stream as hex, base64 or a JSON array of bytes, alternative to crypto-key"
+                ).write_only().mutable_ready().build(),
+                glib::ParamSpecEnum::builder_with_default("crypto-mode", CryptoMode::Lite).nick("Crypto Mode").blurb("The mode used to encrypt the stream, switch-crypto changes it together with the key while streaming").mutable_ready().build(),
+                gst::ParamSpecArray::builder("available-crypto-modes").nick("Available Crypto Modes").blurb(
+                    "The modes offered by the voice server, selects the preferred supported one as crypto-mode"
+                ).element_spec(&glib::ParamSpecString::builder("crypto-mode").build()).mutable_ready().build(),
+                glib::ParamSpecString::builder("address").nick("Address").blurb("The address to stream to as host:port, the host being a name, an IPv4 or a bracketed IPv6 address").build(),
+                glib::ParamSpecString::builder("bind-address").nick("Bind Address").blurb("Local IP address to stream from, any address of the server's IP family if unset").build(),
+                glib::ParamSpecUInt::builder("bind-port").nick("Bind Port").blurb("Local port to stream from, 0 for any").maximum(u16::MAX as u32).build(),
+                glib::ParamSpecObject::builder::<gio::Socket>("socket").nick("Socket").blurb(
+                    "UDP socket to stream from instead of binding one, kept connected if address is unset. The element receives all datagrams arriving on it from READY to NULL"
+                ).build(),
+                glib::ParamSpecBoolean::builder("close-socket").nick("Close Socket").blurb("Close the socket or socket-fd passed as property when going to the NULL state").default_value(true).build(),
                 glib::ParamSpecUInt::builder("video-ssrc").nick("Video ssrc").blurb("The ssrc to use for the rtp video packets").build(),
                 glib::ParamSpecUInt::builder("audio-ssrc").nick("Audio ssrc").blurb("The ssrc to use for the rtp audio packets").build(),
-            ]
+                glib::ParamSpecUInt::builder("mtu").nick("MTU").blurb("Maximum size of the datagrams sent to the server, including all RTP and encryption overhead").minimum(MIN_MTU as u32).maximum(MAX_MTU as u32).default_value(DEFAULT_MTU as u32).mutable_ready().build(),
+                glib::ParamSpecUInt::builder("keepalive-interval").nick("Keepalive Interval").blurb("Milliseconds between UDP keepalives holding NAT bindings open, 0 to disable them").default_value(DEFAULT_KEEPALIVE_INTERVAL_MS).mutable_ready().build(),
+                glib::ParamSpecBoolean::builder("vp9-flexible-mode").nick("VP9 flexible mode").blurb("Use the flexible mode of the VP9 payload descriptor").build(),
+                glib::ParamSpecString::builder("external-ip").nick("External IP").blurb("IP address of the element's socket as seen by the server, known from the READY state on unless a socket is provided").read_only().build(),
+                glib::ParamSpecUInt::builder("external-port").nick("External Port").blurb("Port of the element's socket as seen by the server, known from the READY state on unless a socket is provided").maximum(u16::MAX as u32).read_only().build(),
+                glib::ParamSpecBoxed::builder::<glib::Bytes>("dave-key").nick("DAVE Key").blurb("AES-128 key of the current DAVE key ratchet generation, end-to-end encryption is disabled without one").write_only().mutable_playing().build(),
+                glib::ParamSpecUInt::builder("dave-key-generation").nick("DAVE Key Generation").blurb("Key ratchet generation of dave-key").maximum(u8::MAX as u32).mutable_playing().build(),
+            ];
+
+            #[cfg(unix)]
+            properties.push(glib::ParamSpecInt::builder("socket-fd").nick("Socket Descriptor").blurb(
+                "Descriptor of a UDP socket to stream from like socket, which takes precedence, -1 to bind one"
+            ).minimum(-1).default_value(-1).build());
+
+            properties
         });
 
         PROPERTIES.as_ref()
@@ -284,12 +1213,49 @@ impl ObjectImpl for DiscordStreamer {
         match pspec.name() {
             "crypto-key" => {
                 let mut props = self.props.lock();
-                props.crypto_key = value.get().expect("type checked upstream");
+                props.crypto_key = secret_key(value);
+                props.crypto_changed = true;
+            }
+
+            "crypto-key-string" => {
+                let text = value.get::<Option<&str>>().expect("type checked upstream");
+                let crypto_key = match text.map(crypto::parse_key).transpose() {
+                    Ok(crypto_key) => crypto_key,
+                    Err(err) => {
+                        // Properties are often set before the element has a bus, as in
+                        // gst-launch, so the warning goes to the debug log as well.
+                        warning!(CAT, imp: self, "Ignoring invalid crypto-key-string: {}", err);
+                        gst::element_imp_warning!(self, gst::LibraryError::Settings, ["Ignoring invalid crypto-key-string: {}", err]);
+                        return;
+                    }
+                };
+
+                let mut props = self.props.lock();
+                props.crypto_key = crypto_key;
+                props.crypto_changed = true;
             }
 
             "crypto-mode" => {
                 let mut props = self.props.lock();
                 props.crypto_mode = value.get().expect("type checked upstream");
+                props.crypto_changed = true;
+            }
+
+            "available-crypto-modes" => {
+                let mut props = self.props.lock();
+                let modes = value.get::<gst::Array>().expect("type checked upstream");
+                props.available_crypto_modes = modes.iter().filter_map(|mode| mode.get::<String>().ok()).collect();
+
+                match CryptoMode::negotiate(props.available_crypto_modes.iter().map(String::as_str)) {
+                    Some(mode) => {
+                        debug!(CAT, imp: self, "Selected crypto mode {:?}", mode);
+                        props.crypto_mode = mode;
+                    }
+                    None => {
+                        warning!(CAT, imp: self, "None of the offered crypto modes {:?} is supported", props.available_crypto_modes);
+                    }
+                }
+                props.crypto_changed = true;
             }
 
             "address" => {
@@ -297,6 +1263,32 @@ impl ObjectImpl for DiscordStreamer {
                 props.address = value.get().expect("type checked upstream");
             }
 
+            "bind-address" => {
+                let mut props = self.props.lock();
+                props.bind_address = value.get().expect("type checked upstream");
+            }
+
+            "bind-port" => {
+                let mut props = self.props.lock();
+                props.bind_port = value.get().expect("type checked upstream");
+            }
+
+            "socket" => {
+                let mut props = self.props.lock();
+                props.socket = value.get::<Option<gio::Socket>>().expect("type checked upstream").map(ProvidedSocket);
+            }
+
+            #[cfg(unix)]
+            "socket-fd" => {
+                let mut props = self.props.lock();
+                props.socket_fd = value.get().expect("type checked upstream");
+            }
+
+            "close-socket" => {
+                let mut props = self.props.lock();
+                props.close_socket = value.get().expect("type checked upstream");
+            }
+
             "video-ssrc" => {
                 let mut props = self.props.lock();
                 props.video_ssrc = Some(value.get().expect("type checked upstream"));
@@ -307,17 +1299,65 @@ impl ObjectImpl for DiscordStreamer {
                 props.audio_ssrc = Some(value.get().expect("type checked upstream"));
             }
 
+            "keepalive-interval" => {
+                let mut props = self.props.lock();
+                props.keepalive_interval = value.get().expect("type checked upstream");
+            }
+
+            "mtu" => {
+                let mut props = self.props.lock();
+                props.mtu = value.get().expect("type checked upstream");
+            }
+
+            "vp9-flexible-mode" => {
+                let mut props = self.props.lock();
+                props.vp9_flexible_mode = value.get().expect("type checked upstream");
+            }
+
+            "dave-key" => {
+                let mut props = self.props.lock();
+                let dave_key = secret_key(value);
+                // Recreating the encryptor restarts its nonces.
+                if props.dave_key != dave_key {
+                    props.dave_key = dave_key;
+                    drop(props);
+                    self.update_dave();
+                }
+            }
+
+            "dave-key-generation" => {
+                let mut props = self.props.lock();
+                let generation = value.get().expect("type checked upstream");
+                if props.dave_key_generation != generation {
+                    props.dave_key_generation = generation;
+                    drop(props);
+                    self.update_dave();
+                }
+            }
+
             _ => unimplemented!(),
         }
     }
 
     fn property(&self, _id: usize, pspec: &ParamSpec) -> Value {
         match pspec.name() {
-            "crypto-key" => self.props.lock().crypto_key.to_value(),
             "crypto-mode" => self.props.lock().crypto_mode.to_value(),
+            "available-crypto-modes" => gst::Array::new(&self.props.lock().available_crypto_modes).to_value(),
             "address" => self.props.lock().address.to_value(),
+            "bind-address" => self.props.lock().bind_address.to_value(),
+            "bind-port" => self.props.lock().bind_port.to_value(),
+            "socket" => self.props.lock().socket.as_ref().map(|ProvidedSocket(socket)| socket.clone()).to_value(),
+            #[cfg(unix)]
+            "socket-fd" => self.props.lock().socket_fd.to_value(),
+            "close-socket" => self.props.lock().close_socket.to_value(),
             "video-ssrc" => self.props.lock().video_ssrc.map_or((None as Option<glib::GString>).to_value(), |v| v.to_value()),
             "audio-ssrc" => self.props.lock().audio_ssrc.map_or((None as Option<glib::GString>).to_value(), |v| v.to_value()),
+            "mtu" => self.props.lock().mtu.to_value(),
+            "keepalive-interval" => self.props.lock().keepalive_interval.to_value(),
+            "vp9-flexible-mode" => self.props.lock().vp9_flexible_mode.to_value(),
+            "dave-key-generation" => self.props.lock().dave_key_generation.to_value(),
+            "external-ip" => self.state.lock().as_ref().and_then(|state| state.external_address).map(|address| address.ip().to_string()).to_value(),
+            "external-port" => self.state.lock().as_ref().and_then(|state| state.external_address).map_or(0, |address| u32::from(address.port())).to_value(),
             _ => unimplemented!(),
         }
     }
@@ -347,7 +1387,7 @@ impl ElementImpl for DiscordStreamer {
 
     fn pad_templates() -> &'static [PadTemplate] {
         static PAD_TEMPLATES: Lazy<Vec<PadTemplate>> = Lazy::new(|| {
-            let caps = Caps::builder_full().structure(gst::Structure::builder("video/x-h264").field("stream-format", "byte-stream").field("profile", "baseline").build()).structure(gst::Structure::builder("video/x-vp8").build()).structure(gst::Structure::builder("video/x-vp9").build()).structure(gst::Structure::builder("video/x-av1").build()).build();
+            let caps = Caps::builder_full().structure(gst::Structure::builder("video/x-h264").field("stream-format", "byte-stream").field("alignment", gst::List::new(["au", "nal"])).field("profile", "baseline").build()).structure(gst::Structure::builder("video/x-vp8").build()).structure(gst::Structure::builder("video/x-vp9").build()).structure(gst::Structure::builder("video/x-av1").build()).structure(gst::Structure::builder("application/x-rtp").field("media", "video").field("clock-rate", VIDEO_CLOCK_RATE as i32).field("encoding-name", gst::List::new(["H264", "VP8", "VP9", "AV1"])).build()).build();
 
             let video_sink_pad_template = PadTemplate::new(
                 "video_sink",
@@ -360,7 +1400,7 @@ impl ElementImpl for DiscordStreamer {
                 "audio_sink",
                 gst::PadDirection::Sink,
                 gst::PadPresence::Request,
-                &Caps::new_empty_simple("audio/x-opus"),
+                &Caps::builder_full().structure(gst::Structure::builder("audio/x-opus").build()).structure(gst::Structure::builder("application/x-rtp").field("media", "audio").field("clock-rate", OPUS_CLOCK_RATE as i32).field("encoding-name", "OPUS").build()).build(),
             ).unwrap();
 
             vec![video_sink_pad_template, audio_sink_pad_template]
@@ -374,19 +1414,60 @@ impl ElementImpl for DiscordStreamer {
 
         match transition {
             gst::StateChange::NullToReady => {
-                let props = self.props.lock();
+                // IP discovery may take seconds, properties stay settable meanwhile.
+                let props = {
+                    let mut props = self.props.lock();
+                    props.crypto_changed = false;
+                    props.clone()
+                };
 
                 // Create an internal state struct from the provided properties or
                 // refuse to change state
-                let state_ = State::from_props(&props).map_err(|err| {
+                let state_ = State::from_props(&props, &self.obj()).map_err(|err| {
                     self.post_error_message(err);
+                    self.close_provided_socket();
                     gst::StateChangeError
                 })?;
 
+                let external_address = state_.external_address;
                 let _ = self.state.lock().insert(state_);
+
+                if let Some(external_address) = external_address {
+                    self.post_external_address(external_address);
+                }
+            }
+            gst::StateChange::ReadyToPaused => {
+                // Properties mutable in READY apply once streaming starts.
+                let (mtu, keepalive_interval, crypto) = {
+                    let mut props = self.props.lock();
+                    let crypto = std::mem::take(&mut props.crypto_changed).then(|| State::crypto_from_props(&props));
+                    (props.mtu, State::keepalive_interval(&props), crypto)
+                };
+                let mut state_guard = self.state.lock();
+                let mut stopped_receiver = None;
+                if let Some(state) = state_guard.as_mut() {
+                    state.mtu = mtu as usize;
+                    match crypto {
+                        Some(Ok(crypto)) => state.pending_crypto = Some(crypto),
+                        Some(Err(err)) => warning!(CAT, imp: self, "Keeping the current crypto settings: {:?}", err),
+                        None => (),
+                    }
+                    if state.keepalive_interval != keepalive_interval {
+                        let receiver = State::spawn_receiver(&state.udp_socket, state.audio.ssrc, keepalive_interval, &self.obj())
+                            .map_err(|err| {
+                                self.post_error_message(err);
+                                gst::StateChangeError
+                            })?;
+                        stopped_receiver = Some(std::mem::replace(&mut state.receiver, receiver));
+                        state.keepalive_interval = keepalive_interval;
+                    }
+                }
+                drop(state_guard);
+                // Joining the previous receive thread waits for its socket timeout.
+                drop(stopped_receiver);
             }
             gst::StateChange::ReadyToNull => {
-                let _ = self.state.lock().take();
+                self.stop();
             }
             _ => (),
         }
@@ -394,20 +1475,26 @@ impl ElementImpl for DiscordStreamer {
         let success = self.parent_change_state(transition)?;
 
         if transition == gst::StateChange::ReadyToNull {
-            let _ = self.state.lock().take();
+            self.stop();
+            self.close_provided_socket();
         }
 
         Ok(success)
     }
 
-    //TODO: Implement audio pad sink request
     fn request_new_pad(&self, templ: &PadTemplate, name: Option<&str>, _caps: Option<&Caps>) -> Option<Pad> {
         if templ.name_template() == "audio_sink" {
-            let audio_sink = Pad::builder_with_template(templ, name).chain_function(|pad, parent, buffer| {
+            let audio_sink = Pad::builder_with_template(templ, name).chain_function(|_pad, parent, buffer| {
                 DiscordStreamer::catch_panic_pad_function(
                     parent,
                     || Err(FlowError::Error),
-                    |s| s.audio_sink_chain(pad, buffer),
+                    |s| s.sink_chain(Media::Audio, buffer),
+                )
+            }).event_function(|pad, parent, event| {
+                DiscordStreamer::catch_panic_pad_function(
+                    parent,
+                    || false,
+                    |s| s.sink_event(Media::Audio, pad, event),
                 )
             }).build();
             self.obj().add_pad(&audio_sink).unwrap();
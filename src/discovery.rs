@@ -0,0 +1,128 @@
+//! Discord's IP discovery, which tells the client the address and port its socket
+//! is seen at by the voice server.
+//!
+//! Applications need these to select the UDP protocol on the voice gateway.
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use discortp::discord::{IpDiscoveryPacket, IpDiscoveryType, MutableIpDiscoveryPacket};
+
+/// Time to wait for a response before sending the request again.
+pub const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(1);
+/// Number of requests sent before giving up.
+pub const DISCOVERY_ATTEMPTS: usize = 3;
+
+/// Value of the length field, covering the SSRC, address and port.
+const DISCOVERY_LENGTH: u16 = 70;
+
+/// Reasons the external address could not be discovered.
+#[derive(Debug)]
+pub enum DiscoveryError {
+    /// No response arrived for any of the requests.
+    TimedOut,
+    Io(io::Error),
+}
+
+impl fmt::Display for DiscoveryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiscoveryError::TimedOut => write!(
+                f,
+                "no response after {} requests, {:?} apart",
+                DISCOVERY_ATTEMPTS, DISCOVERY_TIMEOUT
+            ),
+            DiscoveryError::Io(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for DiscoveryError {}
+
+impl From<io::Error> for DiscoveryError {
+    fn from(error: io::Error) -> Self {
+        DiscoveryError::Io(error)
+    }
+}
+
+/// Builds the request for the external address of the sender of `ssrc`.
+pub fn request(ssrc: u32) -> Vec<u8> {
+    let mut request = vec![0u8; IpDiscoveryPacket::const_packet_size()];
+    let mut packet = MutableIpDiscoveryPacket::new(&mut request)
+        .expect("FATAL: Too few bytes for an IP discovery packet.");
+    packet.set_pkt_type(IpDiscoveryType::Request);
+    packet.set_length(DISCOVERY_LENGTH);
+    packet.set_ssrc(ssrc);
+    request
+}
+
+/// Returns the external address carried by a response to the request for `ssrc`,
+/// or `None` if `packet` is no such response.
+pub fn parse_response(packet: &[u8], ssrc: u32) -> Option<SocketAddr> {
+    if packet.len() != IpDiscoveryPacket::const_packet_size() {
+        return None;
+    }
+
+    let response = IpDiscoveryPacket::new(packet)?;
+    if response.get_pkt_type() != IpDiscoveryType::Response
+        || response.get_length() != DISCOVERY_LENGTH
+        || response.get_ssrc() != ssrc
+    {
+        return None;
+    }
+
+    // The address is a null-terminated string.
+    let address = response.get_address_raw();
+    let address = &address[..address.iter().position(|&byte| byte == 0).unwrap_or(address.len())];
+    let ip: IpAddr = std::str::from_utf8(address).ok()?.parse().ok()?;
+
+    Some(SocketAddr::new(ip, response.get_port()))
+}
+
+/// Asks the voice server `socket` is connected to for the external address of the
+/// socket, retrying [`DISCOVERY_ATTEMPTS`] times.
+///
+/// Unrelated datagrams arriving meanwhile are dropped.
+pub fn discover(socket: &UdpSocket, ssrc: u32) -> Result<SocketAddr, DiscoveryError> {
+    let previous_timeout = socket.read_timeout()?;
+    let result = discover_with_retries(socket, ssrc);
+    socket.set_read_timeout(previous_timeout)?;
+    result
+}
+
+fn discover_with_retries(socket: &UdpSocket, ssrc: u32) -> Result<SocketAddr, DiscoveryError> {
+    let request = request(ssrc);
+    // One byte more than a response, so that longer datagrams are not mistaken for one.
+    let mut buf = [0u8; IpDiscoveryPacket::const_packet_size() + 1];
+
+    for _ in 0..DISCOVERY_ATTEMPTS {
+        socket.send(&request)?;
+
+        let deadline = Instant::now() + DISCOVERY_TIMEOUT;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+            socket.set_read_timeout(Some(remaining))?;
+
+            match socket.recv(&mut buf) {
+                Ok(len) => {
+                    if let Some(address) = parse_response(&buf[..len], ssrc) {
+                        return Ok(address);
+                    }
+                }
+                // An unreachable server may be reported for the previous request,
+                // treat it like a lost one.
+                Err(error) if matches!(
+                    error.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::ConnectionRefused
+                ) => break,
+                Err(error) => return Err(error.into()),
+            }
+        }
+    }
+
+    Err(DiscoveryError::TimedOut)
+}
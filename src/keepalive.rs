@@ -0,0 +1,93 @@
+//! UDP keepalives, which hold NAT bindings open while little media is sent.
+//!
+//! A keepalive is an 8 byte datagram starting with a little endian counter, which
+//! the voice server echoes back unchanged.
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Size of a keepalive datagram.
+pub const KEEPALIVE_SIZE: usize = 8;
+/// Default time between keepalives in milliseconds, as used by Discord's clients.
+pub const DEFAULT_KEEPALIVE_INTERVAL_MS: u32 = 5_000;
+/// Number of keepalives in a row without an echo after which the path to the
+/// server is considered dead.
+pub const DEAD_PATH_MISSED_KEEPALIVES: u32 = 5;
+
+/// Number of unanswered keepalives remembered for matching late echoes.
+const MAX_UNANSWERED: usize = 16;
+
+/// Returns the counter of a keepalive datagram, or `None` if `packet` is no keepalive.
+///
+/// An empty RTCP receiver report is 8 bytes as well, but carries the non-zero SSRC
+/// of its sender where a keepalive is zero.
+pub fn parse(packet: &[u8]) -> Option<u32> {
+    if packet.len() != KEEPALIVE_SIZE || packet[4..] != [0; 4] {
+        return None;
+    }
+    Some(u32::from_le_bytes(packet[..4].try_into().unwrap()))
+}
+
+/// Schedules keepalives and tracks their echoes.
+#[derive(Debug)]
+pub struct Keepalive {
+    interval: Duration,
+    /// Counter of the next keepalive.
+    counter: u32,
+    next_send: Instant,
+    /// Counters and send times of the keepalives not echoed yet, oldest first.
+    unanswered: VecDeque<(u32, Instant)>,
+    /// Keepalives in a row which were not echoed before the next one was due.
+    missed: u32,
+}
+
+impl Keepalive {
+    /// Creates a schedule sending the first keepalive one `interval` after `now`.
+    pub fn new(interval: Duration, now: Instant) -> Self {
+        Self {
+            interval,
+            counter: 0,
+            next_send: now + interval,
+            unanswered: VecDeque::new(),
+            missed: 0,
+        }
+    }
+
+    /// Returns the keepalive to send if one is due.
+    pub fn poll(&mut self, now: Instant) -> Option<[u8; KEEPALIVE_SIZE]> {
+        if now < self.next_send {
+            return None;
+        }
+
+        if !self.unanswered.is_empty() {
+            self.missed += 1;
+        }
+        if self.unanswered.len() == MAX_UNANSWERED {
+            self.unanswered.pop_front();
+        }
+        self.unanswered.push_back((self.counter, now));
+
+        let mut packet = [0u8; KEEPALIVE_SIZE];
+        packet[..4].copy_from_slice(&self.counter.to_le_bytes());
+        self.counter = self.counter.wrapping_add(1);
+        self.next_send = now + self.interval;
+
+        Some(packet)
+    }
+
+    /// Records an echoed keepalive, returning its round trip time or `None` if it
+    /// was not sent or already echoed.
+    ///
+    /// Any echo shows the path is alive, so all older keepalives count as answered.
+    pub fn echo(&mut self, counter: u32, now: Instant) -> Option<Duration> {
+        let index = self.unanswered.iter().position(|&(sent_counter, _)| sent_counter == counter)?;
+        let (_, sent) = self.unanswered[index];
+        self.unanswered.clear();
+        self.missed = 0;
+        Some(now - sent)
+    }
+
+    /// Number of keepalives in a row which were not echoed in time.
+    pub fn missed(&self) -> u32 {
+        self.missed
+    }
+}
@@ -1,6 +1,11 @@
 pub mod discordstreamer;
-mod crypto;
+pub mod crypto;
+pub mod dave;
 mod constants;
+mod discovery;
+mod keepalive;
+mod packetizer;
+mod receiver;
 
 use gst::glib;
 
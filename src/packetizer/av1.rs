@@ -0,0 +1,399 @@
+//! AV1 payload format as described in the [AV1 RTP specification].
+//!
+//! [AV1 RTP specification]: https://aomediacodec.github.io/av1-rtp-spec/
+use super::{FrameInfo, Packetizer};
+
+const AGGREGATION_Z: u8 = 0b1000_0000;
+const AGGREGATION_Y: u8 = 0b0100_0000;
+const AGGREGATION_W_SHIFT: u8 = 4;
+const AGGREGATION_N: u8 = 0b0000_1000;
+
+/// Largest number of OBU elements which can be signalled through the W field.
+const AGGREGATION_W_MAX: usize = 3;
+
+const OBU_TYPE_SHIFT: u8 = 3;
+const OBU_TYPE_MASK: u8 = 0b1111;
+const OBU_EXTENSION_FLAG: u8 = 0b0000_0100;
+pub(crate) const OBU_HAS_SIZE_FIELD: u8 = 0b0000_0010;
+
+const OBU_SEQUENCE_HEADER: u8 = 1;
+pub(crate) const OBU_TEMPORAL_DELIMITER: u8 = 2;
+pub(crate) const OBU_TILE_LIST: u8 = 8;
+pub(crate) const OBU_PADDING: u8 = 15;
+
+/// Packetizes AV1 temporal units in low overhead bitstream format.
+///
+/// Temporal delimiter and tile list OBUs are dropped, size fields are removed
+/// from the remaining OBU headers and OBUs are fragmented across packets as needed.
+#[derive(Default)]
+pub struct Av1Packetizer;
+
+impl Packetizer for Av1Packetizer {
+    fn packetize(&mut self, frame: &[u8], info: &FrameInfo, max_payload_size: usize) -> Vec<Vec<u8>> {
+        let obus = split_obus(frame);
+        let new_sequence = info.keyframe
+            && obus.iter().any(|obu| obu_type(obu[0]) == OBU_SEQUENCE_HEADER);
+
+        let mut builder = PacketBuilder::new(max_payload_size);
+        for obu in &obus {
+            let mut rest = &obu[..];
+            while !rest.is_empty() {
+                let available = builder.available();
+                if rest.len() + leb128_size(rest.len()) <= available {
+                    builder.push(rest);
+                    break;
+                }
+
+                let fragment_size = fragment_size(available);
+                if fragment_size == 0 && !builder.elements.is_empty() {
+                    builder.flush(false);
+                    continue;
+                }
+                let fragment_size = fragment_size.max(1);
+
+                builder.push(&rest[..fragment_size]);
+                builder.flush(true);
+                rest = &rest[fragment_size..];
+            }
+        }
+        builder.flush(false);
+
+        if new_sequence {
+            if let Some(first) = builder.payloads.first_mut() {
+                first[0] |= AGGREGATION_N;
+            }
+        }
+
+        builder.payloads
+    }
+}
+
+/// Collects OBU elements into packets.
+struct PacketBuilder {
+    max_payload_size: usize,
+    elements: Vec<Vec<u8>>,
+    /// Aggregation header and length-prefixed elements collected so far.
+    size: usize,
+    /// Whether the first element of the next packet continues a fragmented OBU.
+    continuation: bool,
+    payloads: Vec<Vec<u8>>,
+}
+
+impl PacketBuilder {
+    fn new(max_payload_size: usize) -> Self {
+        Self {
+            max_payload_size,
+            elements: Vec::new(),
+            size: 1,
+            continuation: false,
+            payloads: Vec::new(),
+        }
+    }
+
+    /// Space left for one more length-prefixed element.
+    fn available(&self) -> usize {
+        self.max_payload_size.saturating_sub(self.size)
+    }
+
+    fn push(&mut self, element: &[u8]) {
+        self.size += leb128_size(element.len()) + element.len();
+        self.elements.push(element.to_vec());
+    }
+
+    /// Emits the collected elements, `fragmented` denoting that the last one
+    /// continues in the next packet.
+    fn flush(&mut self, fragmented: bool) {
+        if self.elements.is_empty() {
+            return;
+        }
+
+        let mut header = 0;
+        if self.continuation {
+            header |= AGGREGATION_Z;
+        }
+        if fragmented {
+            header |= AGGREGATION_Y;
+        }
+
+        // With W set the last element is not length-prefixed.
+        let count = self.elements.len();
+        let counted = count <= AGGREGATION_W_MAX;
+        if counted {
+            header |= (count as u8) << AGGREGATION_W_SHIFT;
+        }
+
+        let mut payload = Vec::with_capacity(self.size);
+        payload.push(header);
+        for (index, element) in self.elements.iter().enumerate() {
+            if !(counted && index == count - 1) {
+                write_leb128(&mut payload, element.len());
+            }
+            payload.extend_from_slice(element);
+        }
+        self.payloads.push(payload);
+
+        self.elements.clear();
+        self.size = 1;
+        self.continuation = fragmented;
+    }
+}
+
+/// Splits a temporal unit into OBUs, dropping the ones which must not be sent
+/// and rewriting the headers without `obu_size`.
+fn split_obus(data: &[u8]) -> Vec<Vec<u8>> {
+    parse_obus(data)
+        .into_iter()
+        .filter(|obu| !matches!(obu_type(obu.header[0]), OBU_TEMPORAL_DELIMITER | OBU_TILE_LIST))
+        .map(|obu| {
+            let mut bytes = Vec::with_capacity(obu.header.len() + obu.payload.len());
+            bytes.extend_from_slice(obu.header);
+            bytes[0] &= !OBU_HAS_SIZE_FIELD;
+            bytes.extend_from_slice(obu.payload);
+            bytes
+        })
+        .collect()
+}
+
+/// An OBU of a temporal unit.
+pub(crate) struct Obu<'a> {
+    /// OBU header and extension header as found in the temporal unit.
+    pub header: &'a [u8],
+    pub payload: &'a [u8],
+}
+
+/// Splits a temporal unit in low overhead bitstream format into OBUs, ending at
+/// the first truncated one.
+pub(crate) fn parse_obus(data: &[u8]) -> Vec<Obu<'_>> {
+    let mut obus = Vec::new();
+    let mut offset = 0;
+
+    while offset < data.len() {
+        let header = data[offset];
+        let header_size = if header & OBU_EXTENSION_FLAG != 0 { 2 } else { 1 };
+        let Some(header_bytes) = data.get(offset..offset + header_size) else {
+            break;
+        };
+
+        let (payload_size, size_field) = if header & OBU_HAS_SIZE_FIELD != 0 {
+            match read_leb128(&data[offset + header_size..]) {
+                Some(size) => size,
+                None => break,
+            }
+        } else {
+            (data.len() - offset - header_size, 0)
+        };
+
+        let payload_start = offset + header_size + size_field;
+        let Some(payload) = data.get(payload_start..payload_start + payload_size) else {
+            break;
+        };
+        offset = payload_start + payload_size;
+
+        obus.push(Obu { header: header_bytes, payload });
+    }
+
+    obus
+}
+
+pub(crate) fn obu_type(header: u8) -> u8 {
+    (header >> OBU_TYPE_SHIFT) & OBU_TYPE_MASK
+}
+
+/// Largest fragment which fits in `available` bytes together with its length prefix.
+fn fragment_size(available: usize) -> usize {
+    let mut size = available.saturating_sub(leb128_size(available));
+    while size > 0 && size + leb128_size(size) > available {
+        size -= 1;
+    }
+    size
+}
+
+fn leb128_size(mut value: usize) -> usize {
+    let mut size = 1;
+    while value >= 0x80 {
+        value >>= 7;
+        size += 1;
+    }
+    size
+}
+
+pub(crate) fn write_leb128(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push((value as u8 & 0x7F) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Reads an unsigned LEB128 value, returning it together with its encoded size.
+pub(crate) fn read_leb128(data: &[u8]) -> Option<(usize, usize)> {
+    let mut value = 0usize;
+    for (index, &byte) in data.iter().enumerate().take(8) {
+        value |= usize::from(byte & 0x7F) << (7 * index);
+        if byte & 0x80 == 0 {
+            return Some((value, index + 1));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OBU_METADATA: u8 = 5;
+    const OBU_FRAME: u8 = 6;
+
+    const KEYFRAME: FrameInfo = FrameInfo { keyframe: true, temporal_layer: None, encrypted: false };
+    const INTER_FRAME: FrameInfo = FrameInfo { keyframe: false, temporal_layer: None, encrypted: false };
+
+    /// An OBU with a size field.
+    fn obu(obu_type: u8, payload: &[u8]) -> Vec<u8> {
+        let mut obu = vec![obu_type << OBU_TYPE_SHIFT | OBU_HAS_SIZE_FIELD];
+        write_leb128(&mut obu, payload.len());
+        obu.extend_from_slice(payload);
+        obu
+    }
+
+    /// Splits a payload into its aggregation header and OBU elements.
+    fn elements(payload: &[u8]) -> (u8, Vec<&[u8]>) {
+        let header = payload[0];
+        let count = usize::from(header >> AGGREGATION_W_SHIFT & 0b11);
+        let mut rest = &payload[1..];
+        let mut elements = Vec::new();
+        while !rest.is_empty() {
+            if count != 0 && elements.len() == count - 1 {
+                elements.push(rest);
+                break;
+            }
+            let (size, size_field) = read_leb128(rest).unwrap();
+            elements.push(&rest[size_field..size_field + size]);
+            rest = &rest[size_field + size..];
+        }
+        (header, elements)
+    }
+
+    #[test]
+    fn dropped_obus() {
+        let temporal_unit = [
+            obu(OBU_TEMPORAL_DELIMITER, &[]),
+            obu(OBU_SEQUENCE_HEADER, &[1, 2, 3, 4]),
+            obu(OBU_TILE_LIST, &[9; 6]),
+            obu(OBU_FRAME, &[5, 6, 7, 8, 9]),
+        ].concat();
+
+        // Without size fields.
+        assert_eq!(split_obus(&temporal_unit), [
+            vec![OBU_SEQUENCE_HEADER << OBU_TYPE_SHIFT, 1, 2, 3, 4],
+            vec![OBU_FRAME << OBU_TYPE_SHIFT, 5, 6, 7, 8, 9],
+        ]);
+    }
+
+    #[test]
+    fn extension_header() {
+        let mut frame = obu(OBU_FRAME, &[1, 2, 3]);
+        frame[0] |= OBU_EXTENSION_FLAG;
+        frame.insert(1, 0b0010_0000);
+
+        assert_eq!(split_obus(&frame), [vec![OBU_FRAME << OBU_TYPE_SHIFT | OBU_EXTENSION_FLAG, 0b0010_0000, 1, 2, 3]]);
+    }
+
+    #[test]
+    fn aggregation() {
+        let temporal_unit = [
+            obu(OBU_TEMPORAL_DELIMITER, &[]),
+            obu(OBU_SEQUENCE_HEADER, &[1, 2, 3, 4]),
+            obu(OBU_FRAME, &[5, 6, 7, 8, 9]),
+        ].concat();
+
+        let payloads = Av1Packetizer.packetize(&temporal_unit, &KEYFRAME, 1200);
+        // W = 2, the last element without length prefix.
+        assert_eq!(payloads, vec![vec![
+            2 << AGGREGATION_W_SHIFT | AGGREGATION_N,
+            5, OBU_SEQUENCE_HEADER << OBU_TYPE_SHIFT, 1, 2, 3, 4,
+            OBU_FRAME << OBU_TYPE_SHIFT, 5, 6, 7, 8, 9,
+        ]]);
+    }
+
+    #[test]
+    fn aggregation_without_count() {
+        let temporal_unit: Vec<u8> = (1..=4).flat_map(|byte| obu(OBU_METADATA, &[byte])).collect();
+
+        let payloads = Av1Packetizer.packetize(&temporal_unit, &INTER_FRAME, 1200);
+        let (header, elements) = elements(&payloads[0]);
+        // More than three elements, W = 0 and all of them are length-prefixed.
+        assert_eq!(header, 0);
+        assert_eq!(payloads[0][1..3], [2, OBU_METADATA << OBU_TYPE_SHIFT]);
+        assert_eq!(elements.len(), 4);
+        assert_eq!(elements[3], [OBU_METADATA << OBU_TYPE_SHIFT, 4]);
+    }
+
+    #[test]
+    fn fragmentation() {
+        let payload: Vec<u8> = (0..100).collect();
+        let temporal_unit = [obu(OBU_SEQUENCE_HEADER, &[1, 2, 3, 4]), obu(OBU_FRAME, &payload)].concat();
+        let max_payload_size = 30;
+
+        let payloads = Av1Packetizer.packetize(&temporal_unit, &KEYFRAME, max_payload_size);
+        assert!(payloads.len() > 2);
+        assert!(payloads.iter().all(|payload| payload.len() <= max_payload_size));
+
+        let mut fragments = Vec::new();
+        for (index, payload) in payloads.iter().enumerate() {
+            let (header, elements) = elements(payload);
+            let first = index == 0;
+            let last = index == payloads.len() - 1;
+
+            assert_eq!(header & AGGREGATION_Z != 0, !first, "Z bit of packet {}", index);
+            assert_eq!(header & AGGREGATION_Y != 0, !last, "Y bit of packet {}", index);
+            assert_eq!(header & AGGREGATION_N != 0, first, "N bit of packet {}", index);
+            // The sequence header shares the first packet with the start of the frame.
+            assert_eq!(usize::from(header >> AGGREGATION_W_SHIFT & 0b11), if first { 2 } else { 1 });
+
+            fragments.extend_from_slice(elements.last().unwrap());
+        }
+
+        assert_eq!(fragments[0], OBU_FRAME << OBU_TYPE_SHIFT);
+        assert_eq!(fragments[1..], payload);
+    }
+
+    #[test]
+    fn new_coded_video_sequence() {
+        let with_sequence_header = [obu(OBU_SEQUENCE_HEADER, &[1]), obu(OBU_FRAME, &[2])].concat();
+        let without_sequence_header = obu(OBU_FRAME, &[2]);
+
+        let n_bit = |temporal_unit: &[u8], info: &FrameInfo| {
+            Av1Packetizer.packetize(temporal_unit, info, 1200)[0][0] & AGGREGATION_N != 0
+        };
+        assert!(n_bit(&with_sequence_header, &KEYFRAME));
+        assert!(!n_bit(&with_sequence_header, &INTER_FRAME));
+        assert!(!n_bit(&without_sequence_header, &KEYFRAME));
+    }
+
+    #[test]
+    fn payload_size_limit() {
+        let temporal_unit: Vec<u8> = [obu(OBU_SEQUENCE_HEADER, &[7; 20])].into_iter()
+            .chain((0..10).map(|size| obu(OBU_FRAME, &vec![size; 50 + 40 * usize::from(size)])))
+            .flatten()
+            .collect();
+
+        for max_payload_size in [8, 30, 129, 200, 1200] {
+            let payloads = Av1Packetizer.packetize(&temporal_unit, &KEYFRAME, max_payload_size);
+            assert!(
+                payloads.iter().all(|payload| payload.len() <= max_payload_size),
+                "Payload exceeds {} bytes", max_payload_size,
+            );
+        }
+    }
+
+    #[test]
+    fn leb128() {
+        for value in [0, 1, 127, 128, 300, 16_383, 16_384, 1 << 21] {
+            let mut encoded = Vec::new();
+            write_leb128(&mut encoded, value);
+            assert_eq!(encoded.len(), leb128_size(value));
+            assert_eq!(read_leb128(&encoded), Some((value, encoded.len())));
+        }
+        assert_eq!(fragment_size(129), 127);
+    }
+}
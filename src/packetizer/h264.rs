@@ -0,0 +1,242 @@
+//! H.264 payload format as described in [RFC 6184].
+//!
+//! [RFC 6184]: https://www.rfc-editor.org/rfc/rfc6184
+use super::{FrameInfo, Packetizer};
+
+pub(crate) const NAL_TYPE_MASK: u8 = 0b0001_1111;
+const NAL_NRI_MASK: u8 = 0b0110_0000;
+const NAL_FORBIDDEN_MASK: u8 = 0b1000_0000;
+
+const NAL_TYPE_STAP_A: u8 = 24;
+const NAL_TYPE_FU_A: u8 = 28;
+
+const FU_START: u8 = 0b1000_0000;
+const FU_END: u8 = 0b0100_0000;
+
+/// Size of the big-endian length field preceding each NAL unit in a STAP-A.
+const STAP_A_LENGTH_SIZE: usize = 2;
+
+/// Packetizes Annex B byte-stream access units in non-interleaved mode.
+///
+/// Small NAL units are aggregated into STAP-A packets, NAL units too large
+/// for a single packet are fragmented into FU-A packets and everything else
+/// is sent as a single NAL unit packet.
+#[derive(Default)]
+pub struct H264Packetizer;
+
+impl Packetizer for H264Packetizer {
+    fn packetize(&mut self, frame: &[u8], _info: &FrameInfo, max_payload_size: usize) -> Vec<Vec<u8>> {
+        let mut payloads = Vec::new();
+        let mut aggregate: Vec<&[u8]> = Vec::new();
+        // STAP-A NAL header followed by the length-prefixed units collected so far.
+        let mut aggregate_size = 1;
+
+        for nal in split_nal_units(frame) {
+            if nal.len() > max_payload_size {
+                flush_aggregate(&mut aggregate, &mut payloads);
+                fragment(nal, max_payload_size, &mut payloads);
+                continue;
+            }
+
+            if !aggregate.is_empty()
+                && aggregate_size + STAP_A_LENGTH_SIZE + nal.len() <= max_payload_size
+            {
+                aggregate.push(nal);
+                aggregate_size += STAP_A_LENGTH_SIZE + nal.len();
+                continue;
+            }
+
+            flush_aggregate(&mut aggregate, &mut payloads);
+            aggregate.push(nal);
+            aggregate_size = 1 + STAP_A_LENGTH_SIZE + nal.len();
+        }
+
+        flush_aggregate(&mut aggregate, &mut payloads);
+
+        payloads
+    }
+}
+
+/// Splits an Annex B byte-stream into NAL units, without start codes.
+///
+/// A buffer without any start code is treated as a single NAL unit.
+pub(crate) fn split_nal_units(data: &[u8]) -> Vec<&[u8]> {
+    let mut units = Vec::new();
+    let mut start = None;
+    let mut i = 0;
+
+    while i + 3 <= data.len() {
+        if data[i] == 0 && data[i + 1] == 0 && data[i + 2] == 1 {
+            if let Some(start) = start {
+                units.push(trim_trailing_zeros(&data[start..i]));
+            }
+            i += 3;
+            start = Some(i);
+        } else {
+            i += 1;
+        }
+    }
+
+    match start {
+        Some(start) => units.push(trim_trailing_zeros(&data[start..])),
+        None => units.push(data),
+    }
+
+    units.retain(|unit| !unit.is_empty());
+    units
+}
+
+/// Removes the zero bytes belonging to a following four byte start code or to
+/// `trailing_zero_8bits` padding.
+fn trim_trailing_zeros(unit: &[u8]) -> &[u8] {
+    let end = unit.iter().rposition(|&b| b != 0).map_or(0, |p| p + 1);
+    &unit[..end]
+}
+
+/// Emits the collected NAL units as a single NAL unit packet or a STAP-A.
+fn flush_aggregate(aggregate: &mut Vec<&[u8]>, payloads: &mut Vec<Vec<u8>>) {
+    match aggregate.len() {
+        0 => return,
+        1 => payloads.push(aggregate[0].to_vec()),
+        _ => {
+            let forbidden = aggregate.iter().fold(0, |acc, nal| acc | (nal[0] & NAL_FORBIDDEN_MASK));
+            let nri = aggregate.iter().map(|nal| nal[0] & NAL_NRI_MASK).max().unwrap_or(0);
+
+            let size = 1 + aggregate.iter().map(|nal| STAP_A_LENGTH_SIZE + nal.len()).sum::<usize>();
+            let mut payload = Vec::with_capacity(size);
+            payload.push(forbidden | nri | NAL_TYPE_STAP_A);
+            for nal in aggregate.iter() {
+                payload.extend_from_slice(&(nal.len() as u16).to_be_bytes());
+                payload.extend_from_slice(nal);
+            }
+            payloads.push(payload);
+        }
+    }
+
+    aggregate.clear();
+}
+
+/// Splits a NAL unit into FU-A packets of at most `max_payload_size` bytes.
+fn fragment(nal: &[u8], max_payload_size: usize, payloads: &mut Vec<Vec<u8>>) {
+    let indicator = (nal[0] & (NAL_FORBIDDEN_MASK | NAL_NRI_MASK)) | NAL_TYPE_FU_A;
+    let nal_type = nal[0] & NAL_TYPE_MASK;
+    // FU indicator and FU header precede every fragment.
+    let fragment_size = max_payload_size.saturating_sub(2).max(1);
+
+    let mut chunks = nal[1..].chunks(fragment_size).peekable();
+    let mut first = true;
+    while let Some(chunk) = chunks.next() {
+        let mut header = nal_type;
+        if first {
+            header |= FU_START;
+            first = false;
+        }
+        if chunks.peek().is_none() {
+            header |= FU_END;
+        }
+
+        let mut payload = Vec::with_capacity(2 + chunk.len());
+        payload.push(indicator);
+        payload.push(header);
+        payload.extend_from_slice(chunk);
+        payloads.push(payload);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPS: [u8; 4] = [0x67, 0x42, 0x00, 0x1F];
+    const PPS: [u8; 4] = [0x68, 0xCE, 0x3C, 0x80];
+    const SEI: [u8; 3] = [0x06, 0x05, 0x01];
+
+    fn packetize(frame: &[u8], max_payload_size: usize) -> Vec<Vec<u8>> {
+        H264Packetizer.packetize(frame, &FrameInfo::default(), max_payload_size)
+    }
+
+    #[test]
+    fn single_nal_unit() {
+        let idr = [0x65, 0x88, 0x84, 0x00, 0x21];
+        let frame = [&[0, 0, 0, 1][..], &idr].concat();
+
+        assert_eq!(packetize(&frame, 1200), vec![idr.to_vec()]);
+    }
+
+    #[test]
+    fn stap_a() {
+        let frame = [&[0, 0, 0, 1][..], &SPS, &[0, 0, 1], &PPS, &[0, 0, 1], &SEI].concat();
+
+        let payloads = packetize(&frame, 1200);
+        // The highest NRI of the aggregated units, those of the SPS.
+        let expected = [
+            &[0b0110_0000 | NAL_TYPE_STAP_A, 0, 4][..], &SPS,
+            &[0, 4], &PPS,
+            &[0, 3], &SEI,
+        ].concat();
+        assert_eq!(payloads, vec![expected]);
+    }
+
+    #[test]
+    fn stap_a_forbidden_bit() {
+        let corrupt = [0x86, 0x01];
+        let frame = [&[0, 0, 1][..], &corrupt, &[0, 0, 1], &PPS].concat();
+
+        let payloads = packetize(&frame, 1200);
+        assert_eq!(payloads.len(), 1);
+        assert_eq!(payloads[0][0], NAL_FORBIDDEN_MASK | 0b0110_0000 | NAL_TYPE_STAP_A);
+    }
+
+    #[test]
+    fn stap_a_size_limit() {
+        let frame = [&[0, 0, 1][..], &SPS, &[0, 0, 1], &PPS].concat();
+        // STAP-A header and two length-prefixed units.
+        let aggregate_size = 1 + 2 * (STAP_A_LENGTH_SIZE + 4);
+
+        assert_eq!(packetize(&frame, aggregate_size).len(), 1);
+        assert_eq!(packetize(&frame, aggregate_size - 1), vec![SPS.to_vec(), PPS.to_vec()]);
+    }
+
+    #[test]
+    fn fu_a() {
+        let idr: Vec<u8> = [0x65].into_iter().chain(1..=10).collect();
+        let frame = [&[0, 0, 1][..], &SPS, &[0, 0, 1], &idr].concat();
+        let max_payload_size = 5;
+
+        let payloads = packetize(&frame, max_payload_size);
+        assert_eq!(payloads[0], SPS);
+
+        let fragments = &payloads[1..];
+        let sizes: Vec<usize> = fragments.iter().map(Vec::len).collect();
+        assert_eq!(sizes, [5, 5, 5, 3]);
+
+        let headers: Vec<[u8; 2]> = fragments.iter().map(|fragment| [fragment[0], fragment[1]]).collect();
+        assert_eq!(headers, [
+            [0x60 | NAL_TYPE_FU_A, FU_START | 5],
+            [0x60 | NAL_TYPE_FU_A, 5],
+            [0x60 | NAL_TYPE_FU_A, 5],
+            [0x60 | NAL_TYPE_FU_A, FU_END | 5],
+        ]);
+
+        let reassembled: Vec<u8> = fragments.iter().flat_map(|fragment| fragment[2..].iter().copied()).collect();
+        assert_eq!(reassembled, idr[1..]);
+    }
+
+    #[test]
+    fn start_codes() {
+        let frame = [
+            &[0, 0, 0, 1][..], &SPS,
+            &[0, 0, 1], &PPS,
+            &[0, 0, 0, 1], &SEI,
+            // trailing_zero_8bits
+            &[0, 0],
+        ].concat();
+
+        assert_eq!(split_nal_units(&frame), [&SPS[..], &PPS, &SEI]);
+    }
+
+    #[test]
+    fn no_start_code() {
+        assert_eq!(split_nal_units(&SPS), [&SPS[..]]);
+    }
+}
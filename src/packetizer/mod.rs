@@ -0,0 +1,81 @@
+//! RTP payload formats for the codecs accepted by the streamer.
+pub(crate) mod av1;
+pub(crate) mod h264;
+mod opus;
+mod vp8;
+mod vp9;
+
+pub use av1::Av1Packetizer;
+pub use h264::H264Packetizer;
+pub use opus::OpusPacketizer;
+pub use vp8::Vp8Packetizer;
+pub use vp9::Vp9Packetizer;
+
+use discortp::rtp::RtpType;
+
+use crate::constants::{RTP_AV1_PROFILE_TYPE, RTP_H264_PROFILE_TYPE, RTP_OPUS_PROFILE_TYPE, RTP_VP8_PROFILE_TYPE, RTP_VP9_PROFILE_TYPE};
+
+/// Options applied to newly created packetizers.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PacketizerConfig {
+    /// Use the flexible mode of the VP9 payload descriptor.
+    pub vp9_flexible_mode: bool,
+    /// Width and height of the video frames from the caps, if known.
+    pub frame_size: Option<(u16, u16)>,
+}
+
+/// Per-frame information which is not carried in the bitstream itself.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FrameInfo {
+    /// Whether the frame can be decoded without any previous frame.
+    pub keyframe: bool,
+    /// Temporal scalability information provided by the encoder, if any.
+    pub temporal_layer: Option<TemporalLayer>,
+    /// Whether the frame is end-to-end encrypted, leaving only parts of its
+    /// bitstream readable.
+    pub encrypted: bool,
+}
+
+/// Temporal layer a frame belongs to.
+#[derive(Clone, Copy, Debug)]
+pub struct TemporalLayer {
+    /// Temporal layer index, `0` being the base layer.
+    pub id: u8,
+    /// Whether the frame only depends on the base layer.
+    pub sync: bool,
+    /// Running index of base layer frames.
+    pub tl0_pic_idx: u8,
+}
+
+/// Splits encoded media frames into RTP payloads.
+pub trait Packetizer: Send {
+    /// Packetizes a single encoded frame into payloads of at most `max_payload_size` bytes.
+    ///
+    /// All payloads returned for one call belong to the same frame, in sending order.
+    fn packetize(&mut self, frame: &[u8], info: &FrameInfo, max_payload_size: usize) -> Vec<Vec<u8>>;
+}
+
+/// Returns the RTP payload type and a fresh packetizer for the given caps name,
+/// or `None` if the media type cannot be packetized.
+pub fn for_caps_name(name: &str, config: &PacketizerConfig) -> Option<(RtpType, Box<dyn Packetizer>)> {
+    match name {
+        "video/x-av1" => Some((RTP_AV1_PROFILE_TYPE, Box::<Av1Packetizer>::default())),
+        "video/x-h264" => Some((RTP_H264_PROFILE_TYPE, Box::<H264Packetizer>::default())),
+        "video/x-vp8" => Some((RTP_VP8_PROFILE_TYPE, Box::<Vp8Packetizer>::default())),
+        "audio/x-opus" => Some((RTP_OPUS_PROFILE_TYPE, Box::<OpusPacketizer>::default())),
+        "video/x-vp9" => Some((RTP_VP9_PROFILE_TYPE, Box::new(Vp9Packetizer::new(config.vp9_flexible_mode, config.frame_size)))),
+        _ => None,
+    }
+}
+
+/// Returns the payload type Discord expects for an RTP `encoding-name`.
+pub fn payload_type_for_encoding_name(name: &str) -> Option<RtpType> {
+    match name {
+        "AV1" => Some(RTP_AV1_PROFILE_TYPE),
+        "H264" => Some(RTP_H264_PROFILE_TYPE),
+        "OPUS" => Some(RTP_OPUS_PROFILE_TYPE),
+        "VP8" => Some(RTP_VP8_PROFILE_TYPE),
+        "VP9" => Some(RTP_VP9_PROFILE_TYPE),
+        _ => None,
+    }
+}
@@ -0,0 +1,14 @@
+//! Opus payload format as described in [RFC 7587].
+//!
+//! [RFC 7587]: https://www.rfc-editor.org/rfc/rfc7587
+use super::{FrameInfo, Packetizer};
+
+/// Sends each Opus packet as the unmodified payload of a single RTP packet.
+#[derive(Default)]
+pub struct OpusPacketizer;
+
+impl Packetizer for OpusPacketizer {
+    fn packetize(&mut self, frame: &[u8], _info: &FrameInfo, _max_payload_size: usize) -> Vec<Vec<u8>> {
+        vec![frame.to_vec()]
+    }
+}
@@ -0,0 +1,509 @@
+//! VP8 payload format as described in [RFC 7741].
+//!
+//! [RFC 7741]: https://www.rfc-editor.org/rfc/rfc7741
+use super::{FrameInfo, Packetizer};
+
+const DESCRIPTOR_X: u8 = 0b1000_0000;
+const DESCRIPTOR_S: u8 = 0b0001_0000;
+const DESCRIPTOR_PID_MAX: usize = 0b0111;
+
+const EXTENSION_I: u8 = 0b1000_0000;
+const EXTENSION_L: u8 = 0b0100_0000;
+const EXTENSION_T: u8 = 0b0010_0000;
+
+const PICTURE_ID_M: u8 = 0b1000_0000;
+const PICTURE_ID_MASK: u16 = 0x7FFF;
+
+const TID_Y: u8 = 0b0010_0000;
+
+/// Packetizes VP8 frames with a payload descriptor carrying a 15 bit PictureID
+/// and, when the encoder provides them, the temporal layer fields.
+///
+/// Partition boundaries are read from the frame header unless the frame is
+/// end-to-end encrypted, which is then sent as a single partition.
+pub struct Vp8Packetizer {
+    picture_id: u16,
+}
+
+impl Default for Vp8Packetizer {
+    fn default() -> Self {
+        Self {
+            picture_id: rand::random::<u16>() & PICTURE_ID_MASK,
+        }
+    }
+}
+
+impl Vp8Packetizer {
+    fn descriptor(&self, info: &FrameInfo, start: bool, partition: usize) -> Vec<u8> {
+        let mut descriptor = Vec::with_capacity(6);
+
+        let mut first = DESCRIPTOR_X | partition.min(DESCRIPTOR_PID_MAX) as u8;
+        if start {
+            first |= DESCRIPTOR_S;
+        }
+        descriptor.push(first);
+
+        let mut extension = EXTENSION_I;
+        if info.temporal_layer.is_some() {
+            extension |= EXTENSION_L | EXTENSION_T;
+        }
+        descriptor.push(extension);
+
+        descriptor.push(PICTURE_ID_M | (self.picture_id >> 8) as u8);
+        descriptor.push(self.picture_id as u8);
+
+        if let Some(layer) = info.temporal_layer {
+            descriptor.push(layer.tl0_pic_idx);
+            let mut tid = (layer.id & 0b11) << 6;
+            if layer.sync {
+                tid |= TID_Y;
+            }
+            descriptor.push(tid);
+        }
+
+        descriptor
+    }
+}
+
+impl Packetizer for Vp8Packetizer {
+    fn packetize(&mut self, frame: &[u8], info: &FrameInfo, max_payload_size: usize) -> Vec<Vec<u8>> {
+        let partitions = if info.encrypted { vec![0] } else { partition_offsets(frame) };
+        let descriptor_size = self.descriptor(info, false, 0).len();
+        let chunk_size = max_payload_size.saturating_sub(descriptor_size).max(1);
+
+        let mut payloads = Vec::with_capacity(frame.len() / chunk_size + 1);
+        for (index, chunk) in frame.chunks(chunk_size).enumerate() {
+            let offset = index * chunk_size;
+            let partition = partitions.iter().rposition(|&start| start <= offset).unwrap_or(0);
+            // The first packet of a frame always starts a partition.
+            let start = offset == 0
+                || (partitions[partition] == offset && partition <= DESCRIPTOR_PID_MAX);
+
+            let mut payload = self.descriptor(info, start, partition);
+            payload.extend_from_slice(chunk);
+            payloads.push(payload);
+        }
+
+        self.picture_id = self.picture_id.wrapping_add(1) & PICTURE_ID_MASK;
+
+        payloads
+    }
+}
+
+/// Returns the byte offsets at which each partition of a VP8 frame starts.
+///
+/// Partition 0 holds the frame header, the first partition and the table of
+/// token partition sizes; the DCT token partitions follow. A frame that cannot
+/// be parsed is treated as a single partition.
+fn partition_offsets(frame: &[u8]) -> Vec<usize> {
+    let mut offsets = vec![0];
+
+    if frame.len() < 3 {
+        return offsets;
+    }
+
+    let tag = u32::from(frame[0]) | u32::from(frame[1]) << 8 | u32::from(frame[2]) << 16;
+    let key_frame = tag & 1 == 0;
+    let first_partition_size = (tag >> 5) as usize;
+    let header_size = if key_frame { 10 } else { 3 };
+
+    let first_partition_end = header_size + first_partition_size;
+    if first_partition_end > frame.len() {
+        return offsets;
+    }
+
+    let Some(partition_count) = token_partition_count(&frame[header_size..first_partition_end], key_frame) else {
+        return offsets;
+    };
+
+    let sizes_end = first_partition_end + 3 * (partition_count - 1);
+    if sizes_end > frame.len() {
+        return offsets;
+    }
+
+    let mut start = sizes_end;
+    offsets.push(start);
+    for size in frame[first_partition_end..sizes_end].chunks_exact(3) {
+        start += usize::from(size[0]) | usize::from(size[1]) << 8 | usize::from(size[2]) << 16;
+        if start >= frame.len() {
+            break;
+        }
+        offsets.push(start);
+    }
+
+    offsets
+}
+
+/// Reads the frame header from the first partition up to
+/// `log2_nbr_of_dct_partitions`, see RFC 6386 section 19.2.
+fn token_partition_count(first_partition: &[u8], key_frame: bool) -> Option<usize> {
+    let mut bd = BoolDecoder::new(first_partition)?;
+
+    if key_frame {
+        // color_space and clamping_type
+        bd.literal(2);
+    }
+
+    // segmentation_enabled
+    if bd.flag() {
+        let update_mb_segmentation_map = bd.flag();
+        // update_segment_feature_data
+        if bd.flag() {
+            // segment_feature_mode
+            bd.flag();
+            // quantizer and loop filter updates
+            for bits in [7, 6] {
+                for _ in 0..4 {
+                    if bd.flag() {
+                        bd.literal(bits);
+                        bd.flag();
+                    }
+                }
+            }
+        }
+        if update_mb_segmentation_map {
+            for _ in 0..3 {
+                if bd.flag() {
+                    bd.literal(8);
+                }
+            }
+        }
+    }
+
+    // filter_type, loop_filter_level and sharpness_level
+    bd.literal(1 + 6 + 3);
+
+    // loop_filter_adj_enable
+    if bd.flag() {
+        // mode_ref_lf_delta_update
+        if bd.flag() {
+            for _ in 0..8 {
+                if bd.flag() {
+                    bd.literal(6);
+                    bd.flag();
+                }
+            }
+        }
+    }
+
+    Some(1 << bd.literal(2))
+}
+
+/// Boolean entropy decoder from RFC 6386 section 7, only used with the
+/// even probability needed to read header literals.
+struct BoolDecoder<'a> {
+    data: &'a [u8],
+    position: usize,
+    value: u32,
+    range: u32,
+    bit_count: u32,
+}
+
+impl<'a> BoolDecoder<'a> {
+    fn new(data: &'a [u8]) -> Option<Self> {
+        if data.len() < 2 {
+            return None;
+        }
+
+        Some(Self {
+            data,
+            position: 2,
+            value: u32::from(data[0]) << 8 | u32::from(data[1]),
+            range: 255,
+            bit_count: 0,
+        })
+    }
+
+    fn flag(&mut self) -> bool {
+        let split = 1 + (((self.range - 1) * 128) >> 8);
+        let big_split = split << 8;
+
+        let bit = if self.value >= big_split {
+            self.range -= split;
+            self.value -= big_split;
+            true
+        } else {
+            self.range = split;
+            false
+        };
+
+        while self.range < 128 {
+            self.value <<= 1;
+            self.range <<= 1;
+            self.bit_count += 1;
+            if self.bit_count == 8 {
+                self.bit_count = 0;
+                if let Some(&byte) = self.data.get(self.position) {
+                    self.value |= u32::from(byte);
+                    self.position += 1;
+                }
+            }
+        }
+
+        bit
+    }
+
+    fn literal(&mut self, bits: u32) -> u32 {
+        (0..bits).fold(0, |acc, _| (acc << 1) | u32::from(self.flag()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packetizer::TemporalLayer;
+
+    /// Boolean entropy encoder from RFC 6386 section 7.3, writing even
+    /// probability flags like the header fields read by [`BoolDecoder`].
+    struct BoolEncoder {
+        output: Vec<u8>,
+        range: u32,
+        bottom: u32,
+        bit_count: i32,
+    }
+
+    impl BoolEncoder {
+        fn new() -> Self {
+            Self { output: Vec::new(), range: 255, bottom: 0, bit_count: 24 }
+        }
+
+        fn add_one_to_output(&mut self) {
+            for byte in self.output.iter_mut().rev() {
+                if *byte == 255 {
+                    *byte = 0;
+                } else {
+                    *byte += 1;
+                    return;
+                }
+            }
+        }
+
+        fn flag(&mut self, value: bool) {
+            let split = 1 + (((self.range - 1) * 128) >> 8);
+            if value {
+                self.bottom = self.bottom.wrapping_add(split);
+                self.range -= split;
+            } else {
+                self.range = split;
+            }
+
+            while self.range < 128 {
+                self.range <<= 1;
+                if self.bottom & (1 << 31) != 0 {
+                    self.add_one_to_output();
+                }
+                self.bottom <<= 1;
+                self.bit_count -= 1;
+                if self.bit_count == 0 {
+                    self.output.push((self.bottom >> 24) as u8);
+                    self.bottom &= (1 << 24) - 1;
+                    self.bit_count = 8;
+                }
+            }
+        }
+
+        fn literal(&mut self, value: u32, bits: u32) {
+            for bit in (0..bits).rev() {
+                self.flag((value >> bit) & 1 == 1);
+            }
+        }
+
+        fn finish(mut self) -> Vec<u8> {
+            let mut count = self.bit_count;
+            let mut value = self.bottom;
+            if value & (1 << (32 - count)) != 0 {
+                self.add_one_to_output();
+            }
+            value <<= count & 7;
+            count >>= 3;
+            for _ in 0..count {
+                value <<= 8;
+            }
+            for _ in 0..4 {
+                self.output.push((value >> 24) as u8);
+                value <<= 8;
+            }
+            self.output
+        }
+    }
+
+    /// Builds a frame with the given first partition and token partitions.
+    fn frame(key_frame: bool, first_partition: &[u8], partitions: &[&[u8]]) -> Vec<u8> {
+        let tag = u32::from(!key_frame) | 1 << 4 | (first_partition.len() as u32) << 5;
+        let mut frame = tag.to_le_bytes()[..3].to_vec();
+        if key_frame {
+            // Start code and 320x240 without scaling.
+            frame.extend_from_slice(&[0x9D, 0x01, 0x2A, 0x40, 0x01, 0xF0, 0x00]);
+        }
+        frame.extend_from_slice(first_partition);
+        for partition in &partitions[..partitions.len() - 1] {
+            frame.extend_from_slice(&(partition.len() as u32).to_le_bytes()[..3]);
+        }
+        for partition in partitions {
+            frame.extend_from_slice(partition);
+        }
+        frame
+    }
+
+    #[test]
+    fn key_frame_partitions() {
+        let mut bd = BoolEncoder::new();
+        // color_space and clamping_type
+        bd.literal(0, 2);
+        // segmentation_enabled, update_mb_segmentation_map and update_segment_feature_data
+        bd.literal(0b111, 3);
+        // segment_feature_mode
+        bd.flag(true);
+        // Quantizer update for segment 1 and loop filter update for segment 3.
+        for (bits, segment) in [(7, 1), (6, 3)] {
+            for index in 0..4 {
+                bd.flag(index == segment);
+                if index == segment {
+                    bd.literal(5, bits);
+                    bd.flag(true);
+                }
+            }
+        }
+        // Probability update for the second segment id tree node.
+        for index in 0..3 {
+            bd.flag(index == 1);
+            if index == 1 {
+                bd.literal(200, 8);
+            }
+        }
+        // filter_type, loop_filter_level and sharpness_level
+        bd.literal(0, 1);
+        bd.literal(42, 6);
+        bd.literal(3, 3);
+        // loop_filter_adj_enable and mode_ref_lf_delta_update
+        bd.literal(0b11, 2);
+        for index in 0..8 {
+            bd.flag(index % 3 == 0);
+            if index % 3 == 0 {
+                bd.literal(2, 6);
+                bd.flag(false);
+            }
+        }
+        // log2_nbr_of_dct_partitions
+        bd.literal(2, 2);
+        // The rest of the partition.
+        bd.literal(0x5A5A, 16);
+        let first_partition = bd.finish();
+
+        let frame = frame(true, &first_partition, &[&[1; 5], &[2; 6], &[3; 7], &[4; 8]]);
+        let tokens = 10 + first_partition.len() + 3 * 3;
+        assert_eq!(partition_offsets(&frame), [0, tokens, tokens + 5, tokens + 11, tokens + 18]);
+    }
+
+    #[test]
+    fn inter_frame_partitions() {
+        let mut bd = BoolEncoder::new();
+        // segmentation_enabled
+        bd.flag(false);
+        // filter_type, loop_filter_level and sharpness_level
+        bd.literal(1, 1);
+        bd.literal(7, 6);
+        bd.literal(0, 3);
+        // loop_filter_adj_enable without mode_ref_lf_delta_update
+        bd.literal(0b10, 2);
+        // log2_nbr_of_dct_partitions
+        bd.literal(1, 2);
+        bd.literal(0xFF, 8);
+        let first_partition = bd.finish();
+
+        let frame = frame(false, &first_partition, &[&[1; 9], &[2; 4]]);
+        let tokens = 3 + first_partition.len() + 3;
+        assert_eq!(partition_offsets(&frame), [0, tokens, tokens + 9]);
+    }
+
+    #[test]
+    fn truncated_frame() {
+        assert_eq!(partition_offsets(&[0x10, 0x02, 0x00, 0x9D]), [0]);
+    }
+
+    #[test]
+    fn descriptor() {
+        let mut packetizer = Vp8Packetizer { picture_id: PICTURE_ID_MASK };
+        let info = FrameInfo::default();
+
+        let payloads = packetizer.packetize(&[0xAA; 4], &info, 1200);
+        assert_eq!(payloads, vec![vec![
+            DESCRIPTOR_X | DESCRIPTOR_S,
+            EXTENSION_I,
+            PICTURE_ID_M | 0x7F,
+            0xFF,
+            0xAA, 0xAA, 0xAA, 0xAA,
+        ]]);
+
+        // The 15 bit PictureID wraps around.
+        let payloads = packetizer.packetize(&[0xAA; 4], &info, 1200);
+        assert_eq!(payloads[0][..4], [DESCRIPTOR_X | DESCRIPTOR_S, EXTENSION_I, PICTURE_ID_M, 0x00]);
+    }
+
+    #[test]
+    fn temporal_layer_descriptor() {
+        let mut packetizer = Vp8Packetizer { picture_id: 0x1234 };
+        let info = FrameInfo {
+            keyframe: false,
+            temporal_layer: Some(TemporalLayer { id: 2, sync: true, tl0_pic_idx: 7 }),
+            encrypted: false,
+        };
+
+        let payloads = packetizer.packetize(&[0xAA; 10], &info, 10);
+        assert_eq!(payloads.len(), 3);
+        assert_eq!(payloads[0][..6], [
+            DESCRIPTOR_X | DESCRIPTOR_S,
+            EXTENSION_I | EXTENSION_L | EXTENSION_T,
+            PICTURE_ID_M | 0x12,
+            0x34,
+            7,
+            2 << 6 | TID_Y,
+        ]);
+        // Continuation packets repeat the descriptor without the S bit.
+        for payload in &payloads[1..] {
+            assert_eq!(payload[0], DESCRIPTOR_X);
+            assert_eq!(payload[1..6], payloads[0][1..6]);
+        }
+        assert!(payloads.iter().all(|payload| payload.len() <= 10));
+    }
+
+    #[test]
+    fn partition_start_descriptor() {
+        let mut bd = BoolEncoder::new();
+        // Neither segmentation nor loop filter adjustments, two token partitions.
+        bd.flag(false);
+        bd.literal(0, 10);
+        bd.flag(false);
+        bd.literal(1, 2);
+        let first_partition = bd.finish();
+        let frame = frame(false, &first_partition, &[&[1; 4], &[2; 4]]);
+        let tokens = 3 + first_partition.len() + 3;
+
+        // Split right at the start of the first token partition.
+        let mut packetizer = Vp8Packetizer { picture_id: 0 };
+        let payloads = packetizer.packetize(&frame, &FrameInfo::default(), 4 + tokens);
+
+        assert_eq!(payloads[0][0], DESCRIPTOR_X | DESCRIPTOR_S);
+        assert_eq!(payloads[1][0], DESCRIPTOR_X | DESCRIPTOR_S | 1);
+    }
+
+    #[test]
+    fn encrypted_frame() {
+        let mut bd = BoolEncoder::new();
+        bd.flag(false);
+        bd.literal(0, 10);
+        bd.flag(false);
+        bd.literal(1, 2);
+        let first_partition = bd.finish();
+        let frame = frame(false, &first_partition, &[&[1; 4], &[2; 4]]);
+        let tokens = 3 + first_partition.len() + 3;
+
+        // The partition sizes of an encrypted frame cannot be trusted.
+        let mut packetizer = Vp8Packetizer { picture_id: 0 };
+        let info = FrameInfo { encrypted: true, ..Default::default() };
+        let payloads = packetizer.packetize(&frame, &info, 4 + tokens);
+
+        assert_eq!(payloads[0][0], DESCRIPTOR_X | DESCRIPTOR_S);
+        assert!(payloads[1..].iter().all(|payload| payload[0] == DESCRIPTOR_X));
+    }
+}
@@ -0,0 +1,272 @@
+//! VP9 payload format as described in [RFC 9628].
+//!
+//! [RFC 9628]: https://www.rfc-editor.org/rfc/rfc9628
+use super::{FrameInfo, Packetizer};
+
+const DESCRIPTOR_I: u8 = 0b1000_0000;
+const DESCRIPTOR_P: u8 = 0b0100_0000;
+const DESCRIPTOR_L: u8 = 0b0010_0000;
+const DESCRIPTOR_F: u8 = 0b0001_0000;
+const DESCRIPTOR_B: u8 = 0b0000_1000;
+const DESCRIPTOR_E: u8 = 0b0000_0100;
+const DESCRIPTOR_V: u8 = 0b0000_0010;
+
+const PICTURE_ID_M: u8 = 0b1000_0000;
+const PICTURE_ID_MASK: u16 = 0x7FFF;
+
+const LAYER_U: u8 = 0b0001_0000;
+
+const SS_Y: u8 = 0b0001_0000;
+const SS_G: u8 = 0b0000_1000;
+
+/// Reference index of the previous picture, used when the encoder does not
+/// report its reference structure.
+const P_DIFF_PREVIOUS: u8 = 1;
+
+/// Packetizes VP9 frames of a single spatial layer.
+///
+/// In non-flexible mode every frame carries its layer indices and TL0PICIDX,
+/// and keyframes describe the picture group in the scalability structure.
+/// In flexible mode each inter frame instead lists its reference pictures.
+///
+/// The bitstream is never parsed, as it may be end-to-end encrypted.
+pub struct Vp9Packetizer {
+    flexible: bool,
+    frame_size: Option<(u16, u16)>,
+    picture_id: u16,
+    tl0_pic_idx: u8,
+}
+
+impl Vp9Packetizer {
+    pub fn new(flexible: bool, frame_size: Option<(u16, u16)>) -> Self {
+        Self {
+            flexible,
+            frame_size,
+            picture_id: rand::random::<u16>() & PICTURE_ID_MASK,
+            tl0_pic_idx: rand::random(),
+        }
+    }
+
+    fn descriptor(&self, info: &FrameInfo, first: bool, last: bool) -> Vec<u8> {
+        let mut descriptor = Vec::with_capacity(16);
+
+        let mut flags = DESCRIPTOR_I | DESCRIPTOR_L;
+        if !info.keyframe {
+            flags |= DESCRIPTOR_P;
+        }
+        if self.flexible {
+            flags |= DESCRIPTOR_F;
+        }
+        if first {
+            flags |= DESCRIPTOR_B;
+        }
+        if last {
+            flags |= DESCRIPTOR_E;
+        }
+        let scalability_structure = first && info.keyframe;
+        if scalability_structure {
+            flags |= DESCRIPTOR_V;
+        }
+        descriptor.push(flags);
+
+        descriptor.push(PICTURE_ID_M | (self.picture_id >> 8) as u8);
+        descriptor.push(self.picture_id as u8);
+
+        // Only a single spatial layer is sent, so SID and D are always zero.
+        let (tid, sync, tl0_pic_idx) = match info.temporal_layer {
+            Some(layer) => (layer.id, layer.sync, layer.tl0_pic_idx),
+            None => (0, false, self.tl0_pic_idx),
+        };
+        let mut layer = (tid & 0b111) << 5;
+        if sync {
+            layer |= LAYER_U;
+        }
+        descriptor.push(layer);
+
+        if self.flexible {
+            if !info.keyframe {
+                descriptor.push(P_DIFF_PREVIOUS << 1);
+            }
+        } else {
+            descriptor.push(tl0_pic_idx);
+        }
+
+        if scalability_structure {
+            // N_S is zero for the single spatial layer.
+            let mut ss = 0;
+            if self.frame_size.is_some() {
+                ss |= SS_Y;
+            }
+            if !self.flexible {
+                ss |= SS_G;
+            }
+            descriptor.push(ss);
+            if let Some((width, height)) = self.frame_size {
+                descriptor.extend_from_slice(&width.to_be_bytes());
+                descriptor.extend_from_slice(&height.to_be_bytes());
+            }
+
+            if !self.flexible {
+                // A picture group of one base layer frame referencing its predecessor.
+                descriptor.push(1);
+                descriptor.push(1 << 2);
+                descriptor.push(P_DIFF_PREVIOUS);
+            }
+        }
+
+        descriptor
+    }
+}
+
+impl Packetizer for Vp9Packetizer {
+    fn packetize(&mut self, frame: &[u8], info: &FrameInfo, max_payload_size: usize) -> Vec<Vec<u8>> {
+        let mut payloads = Vec::new();
+        let mut offset = 0;
+        while offset < frame.len() || payloads.is_empty() {
+            let first = offset == 0;
+            // The descriptor size does not depend on the E bit.
+            let descriptor_size = self.descriptor(info, first, false).len();
+            let chunk_size = max_payload_size.saturating_sub(descriptor_size).max(1);
+            let end = frame.len().min(offset + chunk_size);
+
+            let mut payload = self.descriptor(info, first, end == frame.len());
+            payload.extend_from_slice(&frame[offset..end]);
+            payloads.push(payload);
+
+            offset = end;
+        }
+
+        self.picture_id = self.picture_id.wrapping_add(1) & PICTURE_ID_MASK;
+        if !matches!(info.temporal_layer, Some(layer) if layer.id != 0) {
+            self.tl0_pic_idx = self.tl0_pic_idx.wrapping_add(1);
+        }
+
+        payloads
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packetizer::TemporalLayer;
+
+    const KEYFRAME: FrameInfo = FrameInfo { keyframe: true, temporal_layer: None, encrypted: false };
+    const INTER_FRAME: FrameInfo = FrameInfo { keyframe: false, temporal_layer: None, encrypted: false };
+
+    fn packetizer(flexible: bool, frame_size: Option<(u16, u16)>) -> Vp9Packetizer {
+        Vp9Packetizer { flexible, frame_size, picture_id: 0x7FFF, tl0_pic_idx: 9 }
+    }
+
+    #[test]
+    fn non_flexible_keyframe() {
+        let mut packetizer = packetizer(false, Some((1280, 720)));
+
+        let payloads = packetizer.packetize(&[0xAA; 4], &KEYFRAME, 1200);
+        assert_eq!(payloads, vec![vec![
+            DESCRIPTOR_I | DESCRIPTOR_L | DESCRIPTOR_B | DESCRIPTOR_E | DESCRIPTOR_V,
+            PICTURE_ID_M | 0x7F,
+            0xFF,
+            // TID, U, SID and D
+            0,
+            // TL0PICIDX
+            9,
+            // N_S = 0, Y and G
+            SS_Y | SS_G,
+            0x05, 0x00, 0x02, 0xD0,
+            // N_G, then TID = 0, U = 0, R = 1 and P_DIFF of the single entry
+            1, 1 << 2, P_DIFF_PREVIOUS,
+            0xAA, 0xAA, 0xAA, 0xAA,
+        ]]);
+    }
+
+    #[test]
+    fn non_flexible_inter_frame() {
+        let mut packetizer = packetizer(false, Some((1280, 720)));
+        packetizer.packetize(&[0xAA; 4], &KEYFRAME, 1200);
+
+        let payloads = packetizer.packetize(&[0xBB; 4], &INTER_FRAME, 1200);
+        // The PictureID wraps around and TL0PICIDX advances with each base layer frame.
+        assert_eq!(payloads, vec![vec![
+            DESCRIPTOR_I | DESCRIPTOR_P | DESCRIPTOR_L | DESCRIPTOR_B | DESCRIPTOR_E,
+            PICTURE_ID_M, 0x00,
+            0,
+            10,
+            0xBB, 0xBB, 0xBB, 0xBB,
+        ]]);
+    }
+
+    #[test]
+    fn flexible_keyframe() {
+        let mut packetizer = packetizer(true, Some((320, 240)));
+
+        let payloads = packetizer.packetize(&[0xAA; 4], &KEYFRAME, 1200);
+        // No P_DIFF and no picture group description.
+        assert_eq!(payloads, vec![vec![
+            DESCRIPTOR_I | DESCRIPTOR_L | DESCRIPTOR_F | DESCRIPTOR_B | DESCRIPTOR_E | DESCRIPTOR_V,
+            PICTURE_ID_M | 0x7F, 0xFF,
+            0,
+            SS_Y,
+            0x01, 0x40, 0x00, 0xF0,
+            0xAA, 0xAA, 0xAA, 0xAA,
+        ]]);
+    }
+
+    #[test]
+    fn flexible_inter_frame() {
+        let mut packetizer = packetizer(true, Some((320, 240)));
+        let info = FrameInfo {
+            keyframe: false,
+            temporal_layer: Some(TemporalLayer { id: 1, sync: true, tl0_pic_idx: 3 }),
+            encrypted: false,
+        };
+
+        let payloads = packetizer.packetize(&[0xBB; 4], &info, 1200);
+        assert_eq!(payloads, vec![vec![
+            DESCRIPTOR_I | DESCRIPTOR_P | DESCRIPTOR_L | DESCRIPTOR_F | DESCRIPTOR_B | DESCRIPTOR_E,
+            PICTURE_ID_M | 0x7F, 0xFF,
+            1 << 5 | LAYER_U,
+            // P_DIFF with N unset
+            P_DIFF_PREVIOUS << 1,
+            0xBB, 0xBB, 0xBB, 0xBB,
+        ]]);
+    }
+
+    #[test]
+    fn unknown_frame_size() {
+        let mut packetizer = packetizer(false, None);
+
+        let payloads = packetizer.packetize(&[0xAA; 4], &KEYFRAME, 1200);
+        assert_eq!(payloads[0][5..9], [SS_G, 1, 1 << 2, P_DIFF_PREVIOUS]);
+    }
+
+    #[test]
+    fn fragmented_keyframe() {
+        let mut packetizer = packetizer(false, Some((1280, 720)));
+        let frame: Vec<u8> = (0..100).collect();
+
+        let payloads = packetizer.packetize(&frame, &KEYFRAME, 30);
+        assert!(payloads.iter().all(|payload| payload.len() <= 30));
+
+        let flags: Vec<u8> = payloads.iter().map(|payload| payload[0] & (DESCRIPTOR_B | DESCRIPTOR_E | DESCRIPTOR_V)).collect();
+        assert_eq!(flags.first(), Some(&(DESCRIPTOR_B | DESCRIPTOR_V)));
+        assert_eq!(flags.last(), Some(&DESCRIPTOR_E));
+        assert!(flags[1..flags.len() - 1].iter().all(|&flag| flag == 0));
+
+        // The scalability structure is only sent with the first packet.
+        let first_descriptor = 13;
+        let reassembled: Vec<u8> = payloads.iter().enumerate().flat_map(|(index, payload)| {
+            payload[if index == 0 { first_descriptor } else { 5 }..].iter().copied()
+        }).collect();
+        assert_eq!(reassembled, frame);
+    }
+
+    #[test]
+    fn encrypted_frame_ignored() {
+        // End-to-end encrypted frames look like anything, only the frame info counts.
+        let mut packetizer = packetizer(false, Some((1280, 720)));
+        let looks_like_keyframe = [0x82, 0x49, 0x83, 0x42, 0x00];
+
+        let payloads = packetizer.packetize(&looks_like_keyframe, &INTER_FRAME, 1200);
+        assert_eq!(payloads[0][0] & (DESCRIPTOR_P | DESCRIPTOR_V), DESCRIPTOR_P);
+    }
+}
@@ -0,0 +1,179 @@
+//! Receive loop for the datagrams the voice server sends back over the element's socket.
+//!
+//! The loop also sends the keepalives, so that it can match their echoes.
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use discortp::demux::{demux, Demuxed};
+use discortp::rtcp::RtcpPacket;
+use discortp::rtp::RtpPacket;
+
+use crate::constants::RTP_VERSION;
+use crate::discovery;
+use crate::keepalive::{self, Keepalive};
+
+/// How long a blocked receive waits before checking whether the loop was stopped.
+const RECEIVE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Largest datagram received, anything longer is truncated.
+const RECEIVE_BUFFER_SIZE: usize = 2048;
+
+/// A datagram received from the voice server.
+#[derive(Debug)]
+pub enum Datagram<'a> {
+    /// A response to an IP discovery request, with the external address it carries.
+    Discovery(SocketAddr),
+    /// An echoed keepalive, with its counter.
+    Keepalive(u32),
+    Rtp(RtpPacket<'a>),
+    Rtcp(RtcpPacket<'a>),
+    /// Anything else.
+    Unknown(&'a [u8]),
+}
+
+impl<'a> Datagram<'a> {
+    /// Demultiplexes a datagram, `ssrc` being the one IP discovery was requested for.
+    pub fn classify(packet: &'a [u8], ssrc: u32) -> Self {
+        if let Some(address) = discovery::parse_response(packet, ssrc) {
+            return Datagram::Discovery(address);
+        }
+
+        if let Some(counter) = keepalive::parse(packet) {
+            return Datagram::Keepalive(counter);
+        }
+
+        // IP discovery packets start with a zero byte and never look like RTP version 2.
+        if !matches!(packet.first(), Some(byte) if byte >> 6 == RTP_VERSION) {
+            return Datagram::Unknown(packet);
+        }
+
+        match demux(packet) {
+            Demuxed::Rtp(rtp) => Datagram::Rtp(rtp),
+            Demuxed::Rtcp(rtcp) => Datagram::Rtcp(rtcp),
+            Demuxed::FailedParse(_) | Demuxed::TooSmall => Datagram::Unknown(packet),
+        }
+    }
+}
+
+/// What happened on the receive thread.
+#[derive(Debug)]
+pub enum Event<'a> {
+    /// A datagram arrived, apart from echoes of sent keepalives.
+    Received(Datagram<'a>),
+    /// A keepalive was echoed after the given round trip time.
+    KeepaliveEchoed(Duration),
+    /// A keepalive was due while the previous ones were not echoed, holds how many
+    /// were missed in a row.
+    KeepaliveMissed(u32),
+    Error(io::Error),
+}
+
+/// Thread owning the read side of the socket, stopped and joined when dropped.
+pub struct ReceiveThread {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl ReceiveThread {
+    /// Starts receiving on a clone of `socket`, passing every datagram to `handler`,
+    /// and sends keepalives every `keepalive_interval` unless it is `None`.
+    ///
+    /// Socket errors are passed on as well. The loop keeps running after a refused
+    /// connection, which only reports an earlier datagram as undeliverable, and ends
+    /// after any other error.
+    pub fn spawn<F>(socket: &UdpSocket, ssrc: u32, keepalive_interval: Option<Duration>, mut handler: F) -> io::Result<Self>
+    where
+        F: FnMut(Event) + Send + 'static,
+    {
+        let socket = socket.try_clone()?;
+        socket.set_read_timeout(Some(RECEIVE_POLL_INTERVAL))?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = thread::Builder::new().name("discordstreamer-receive".into()).spawn({
+            let stop = stop.clone();
+            move || {
+                let mut keepalive = keepalive_interval.map(|interval| Keepalive::new(interval, Instant::now()));
+                let mut buf = [0u8; RECEIVE_BUFFER_SIZE];
+
+                while !stop.load(Ordering::Acquire) {
+                    if let Some(keepalive) = &mut keepalive {
+                        if let Some(packet) = keepalive.poll(Instant::now()) {
+                            if keepalive.missed() > 0 {
+                                handler(Event::KeepaliveMissed(keepalive.missed()));
+                            }
+                            if let Err(error) = socket.send(&packet) {
+                                handler(Event::Error(error));
+                            }
+                        }
+                    }
+
+                    let result = socket.recv(&mut buf);
+                    let event = match result {
+                        Ok(len) => match Datagram::classify(&buf[..len], ssrc) {
+                            Datagram::Keepalive(counter) => {
+                                match keepalive.as_mut().and_then(|keepalive| keepalive.echo(counter, Instant::now())) {
+                                    Some(round_trip) => Event::KeepaliveEchoed(round_trip),
+                                    None => Event::Received(Datagram::Keepalive(counter)),
+                                }
+                            }
+                            datagram => Event::Received(datagram),
+                        },
+                        Err(error) if matches!(error.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => continue,
+                        Err(error) if error.kind() == io::ErrorKind::ConnectionRefused => Event::Error(error),
+                        Err(error) => {
+                            handler(Event::Error(error));
+                            break;
+                        }
+                    };
+                    handler(event);
+                }
+            }
+        })?;
+
+        Ok(Self { stop, handle: Some(handle) })
+    }
+}
+
+impl Drop for ReceiveThread {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        if let Some(handle) = self.handle.take() {
+            // The handler may drop the last reference to the owner of the thread, the
+            // loop then ends on its own after the handler returns.
+            if handle.thread().id() != thread::current().id() {
+                let _ = handle.join();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{mpsc, Mutex};
+
+    #[test]
+    fn dropped_by_handler() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.connect(server.local_addr().unwrap()).unwrap();
+
+        let owner: Arc<Mutex<Option<ReceiveThread>>> = Arc::default();
+        let (handled, handler_returned) = mpsc::channel();
+        let thread = ReceiveThread::spawn(&socket, 1, None, {
+            let owner = owner.clone();
+            move |_| {
+                drop(owner.lock().unwrap().take());
+                let _ = handled.send(());
+            }
+        }).unwrap();
+        *owner.lock().unwrap() = Some(thread);
+
+        server.send_to(&[1, 2, 3], socket.local_addr().unwrap()).unwrap();
+        handler_returned.recv_timeout(Duration::from_secs(1)).expect("Handler did not return");
+    }
+}
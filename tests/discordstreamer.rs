@@ -1,7 +1,21 @@
+use std::collections::HashSet;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use std::thread;
+use std::num::Wrapping;
 use std::thread::sleep;
+use std::time::Duration;
+use gio::prelude::*;
 use gst::prelude::*;
+use gst::glib::translate::IntoGlib;
 use gst::{debug_bin_to_dot_data, DebugGraphDetails, glib};
+use discordstreamer::crypto::{self, Cipher, CryptoMode, CryptoState, DecryptError, KeyError, NonceReuseDetector};
+use discordstreamer::dave::{self, Codec, FrameEncryptor};
 use discordstreamer::discordstreamer::DiscordStreamer;
+use discortp::Packet;
+use discortp::rtp::MutableRtpPacket;
 
 fn init() {
     use std::sync::Once;
@@ -23,14 +37,208 @@ fn init_tests_dir() {
     })
 }
 
+/// Address the voice server reports for the element in IP discovery responses.
+const EXTERNAL_ADDRESS: &str = "203.0.113.7:50004";
+
+/// Builds an IP discovery packet, of type 1 for requests and 2 for responses.
+fn discovery_packet(packet_type: u16, ssrc: u32, address: SocketAddr) -> Vec<u8> {
+    let mut packet = vec![0u8; 74];
+    packet[0..2].copy_from_slice(&packet_type.to_be_bytes());
+    packet[2..4].copy_from_slice(&70u16.to_be_bytes());
+    packet[4..8].copy_from_slice(&ssrc.to_be_bytes());
+    let ip = address.ip().to_string();
+    packet[8..8 + ip.len()].copy_from_slice(ip.as_bytes());
+    packet[72..74].copy_from_slice(&address.port().to_be_bytes());
+    packet
+}
+
+/// Returns the SSRC of an IP discovery request.
+fn discovery_request_ssrc(packet: &[u8]) -> Option<u32> {
+    (packet.len() == 74 && packet[0..4] == [0, 1, 0, 70]).then(|| u32::from_be_bytes(packet[4..8].try_into().unwrap()))
+}
+
+/// Returns the counter of a keepalive.
+fn keepalive_counter(packet: &[u8]) -> Option<u32> {
+    (packet.len() == 8 && packet[4..] == [0; 4]).then(|| u32::from_le_bytes(packet[..4].try_into().unwrap()))
+}
+
+/// Local stand-in for the Discord voice server.
+///
+/// A background thread answers IP discovery requests with [`EXTERNAL_ADDRESS`],
+/// echoes keepalives and passes all other datagrams on to the test.
+struct VoiceServer {
+    socket: UdpSocket,
+    /// Address of the last element which requested IP discovery.
+    client: Arc<Mutex<Option<SocketAddr>>>,
+    packets: Receiver<Vec<u8>>,
+}
+
+impl VoiceServer {
+    fn local_addr(&self) -> SocketAddr {
+        self.socket.local_addr().unwrap()
+    }
+
+    /// Address the element sends from.
+    fn client_addr(&self) -> Option<SocketAddr> {
+        *self.client.lock().unwrap()
+    }
+
+    /// Sends a datagram to the element.
+    fn send(&self, packet: &[u8]) {
+        let client = self.client.lock().unwrap().expect("No element connected");
+        self.socket.send_to(packet, client).expect("Failed to send to discord_streamer");
+    }
+
+    fn recv(&self) -> Vec<u8> {
+        self.packets.recv_timeout(Duration::from_secs(10)).expect("No packet received from discord_streamer")
+    }
+}
+
+fn voice_server() -> VoiceServer {
+    voice_server_at("127.0.0.1:0")
+}
+
+fn voice_server_at(address: &str) -> VoiceServer {
+    let socket = UdpSocket::bind(address).expect("Failed to bind voice server socket");
+    socket.set_read_timeout(Some(Duration::from_secs(10))).expect("Failed to set read timeout");
+    let client = Arc::new(Mutex::new(None));
+
+    let (sender, packets) = mpsc::channel();
+    thread::spawn({
+        let socket = socket.try_clone().unwrap();
+        let client = client.clone();
+        move || {
+            let mut buf = [0u8; 2048];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf) {
+                if let Some(ssrc) = discovery_request_ssrc(&buf[..len]) {
+                    *client.lock().unwrap() = Some(peer);
+                    let response = discovery_packet(2, ssrc, EXTERNAL_ADDRESS.parse().unwrap());
+                    socket.send_to(&response, peer).expect("Failed to answer IP discovery");
+                } else if keepalive_counter(&buf[..len]).is_some() {
+                    socket.send_to(&buf[..len], peer).expect("Failed to echo keepalive");
+                } else if sender.send(buf[..len].to_vec()).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+
+    VoiceServer { socket, client, packets }
+}
+
+/// Receives datagrams until an RTP packet with the given payload type arrives.
+fn receive_rtp(server: &VoiceServer, payload_type: u8) -> Vec<u8> {
+    loop {
+        let packet = server.recv();
+        if packet.len() >= 12 && packet[0] >> 6 == 2 && packet[1] & 0x7F == payload_type {
+            return packet;
+        }
+    }
+}
+
+/// Receives RTP packets of the given payload type until `frames` packets with
+/// the marker bit set have arrived.
+fn receive_frames(server: &VoiceServer, payload_type: u8, frames: usize) -> Vec<Vec<u8>> {
+    let mut packets: Vec<Vec<u8>> = Vec::new();
+    while packets.iter().filter(|packet| marker(packet)).count() < frames {
+        packets.push(receive_rtp(server, payload_type));
+    }
+    packets
+}
+
+fn marker(packet: &[u8]) -> bool {
+    packet[1] & 0x80 != 0
+}
+
+fn timestamp(packet: &[u8]) -> u32 {
+    u32::from_be_bytes(packet[4..8].try_into().unwrap())
+}
+
+/// Checks that exactly the last packet of each frame carries the marker bit.
+fn assert_frame_markers(packets: &[Vec<u8>]) {
+    for pair in packets.windows(2) {
+        let frame_ends = timestamp(&pair[0]) != timestamp(&pair[1]);
+        assert_eq!(marker(&pair[0]), frame_ends, "Marker bit does not match the frame boundary");
+    }
+    assert!(marker(packets.last().unwrap()));
+}
+
+/// Builds a pipeline from `description` whose `discordstreamer` named `streamer`
+/// sends to `server`.
+fn streaming_pipeline(description: &str, server: &VoiceServer) -> gst::Pipeline {
+    let pipeline = gst::parse_launch(description)
+        .expect("Failed to parse pipeline")
+        .downcast::<gst::Pipeline>()
+        .expect("Not a pipeline");
+
+    let discord_streamer = pipeline.by_name("streamer").expect("No streamer in pipeline");
+    discord_streamer.set_property("crypto-key", glib::Bytes::from_static(&[0; 32]).to_value());
+    discord_streamer.set_property("address", server.local_addr().to_string().to_value());
+    discord_streamer.set_property("video-ssrc", 1234u32.to_value());
+    discord_streamer.set_property("audio-ssrc", 1235u32.to_value());
+
+    pipeline
+}
+
+/// Fails the test if the pipeline posted an error.
+fn assert_no_error(pipeline: &gst::Pipeline) {
+    let bus = pipeline.bus().unwrap();
+    if let Some(msg) = bus.pop_filtered(&[gst::MessageType::Error]) {
+        panic!("Pipeline posted an error: {:?}", msg);
+    }
+}
+
+/// Encrypts an RTP packet the way the element lays it out on the wire.
+fn encrypt_packet(state: &mut CryptoState, key: &[u8], header: &[u8], payload: &[u8]) -> Vec<u8> {
+    let mode = state.kind();
+    let cipher = Cipher::new(mode, key).expect("Failed to create cipher");
+    let prefix_len = mode.payload_prefix_len();
+
+    let mut packet = vec![0u8; header.len() + payload.len() + mode.payload_overhead()];
+    packet[..header.len()].copy_from_slice(header);
+    packet[header.len() + prefix_len..header.len() + prefix_len + payload.len()].copy_from_slice(payload);
+
+    let mut rtp = MutableRtpPacket::new(&mut packet[..]).unwrap();
+    let payload_len = state.write_packet_nonce(&mut rtp, prefix_len + payload.len());
+    mode.encrypt_in_place(&mut rtp, &cipher, payload_len).expect("Failed to encrypt packet");
+
+    packet.truncate(header.len() + payload_len);
+    packet
+}
+
+/// Decrypts a packet sent by the element, returning its plaintext RTP payload.
+fn decrypt_packet(mode: CryptoMode, key: &[u8], packet: &[u8]) -> Result<Vec<u8>, DecryptError> {
+    let cipher = Cipher::new(mode, key).expect("Failed to create cipher");
+    let mut packet = packet.to_vec();
+    let mut rtp = MutableRtpPacket::new(&mut packet[..]).expect("Packet too short for an RTP header");
+    let range = mode.decrypt_in_place(&mut rtp, &cipher)?;
+    Ok(rtp.payload()[range].to_vec())
+}
+
+const ALL_CRYPTO_MODES: [CryptoMode; 5] = [
+    CryptoMode::Normal,
+    CryptoMode::Suffix,
+    CryptoMode::Lite,
+    CryptoMode::Aes256Gcm,
+    CryptoMode::XChaCha20Poly1305,
+];
+
+fn hex(data: &str) -> Vec<u8> {
+    (0..data.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&data[i..i + 2], 16).unwrap())
+        .collect()
+}
+
 #[test]
 fn pipeline_creation_test() {
     init();
+    let server = voice_server();
     let pipeline = gst::Pipeline::new(None);
 
     let discord_streamer = DiscordStreamer::default();
     discord_streamer.set_property("crypto-key", glib::Bytes::from_static(&[0; 32]).to_value());
-    discord_streamer.set_property("address", &"0.0.0.0:8080".to_value());
+    discord_streamer.set_property("address", server.local_addr().to_string().to_value());
     discord_streamer.set_property("video-ssrc", 0000u32.to_value());
     discord_streamer.set_property("audio-ssrc", 0000u32.to_value());
 
@@ -78,4 +286,1479 @@ fn pipeline_creation_test() {
         "./target/debug/tests/pipeline.dot",
         out.as_str(),
     ).unwrap();
-}
\ No newline at end of file
+}
+
+#[test]
+fn unknown_framerate_test() {
+    init();
+    let server = voice_server();
+
+    // Screen capture sources announce a framerate of 0/1, timing comes from the buffers only.
+    let pipeline = streaming_pipeline(
+        "videotestsrc ! video/x-raw,framerate=0/1 ! videoconvert ! x264enc tune=zerolatency ! discordstreamer name=streamer",
+        &server,
+    );
+
+    pipeline.set_state(gst::State::Playing).expect("Failed to set pipeline state");
+
+    let packet = receive_rtp(&server, 103);
+    assert_eq!(u32::from_be_bytes(packet[8..12].try_into().unwrap()), 1234);
+
+    assert_no_error(&pipeline);
+    pipeline.set_state(gst::State::Null).expect("Failed to stop pipeline");
+}
+
+fn frame_marker_test(encoder: &str, payload_type: u8) {
+    init();
+    let server = voice_server();
+
+    let pipeline = streaming_pipeline(
+        &format!("videotestsrc num-buffers=10 ! video/x-raw,width=1280,height=720 ! videoconvert ! {} ! discordstreamer name=streamer", encoder),
+        &server,
+    );
+
+    pipeline.set_state(gst::State::Playing).expect("Failed to set pipeline state");
+
+    let packets = receive_frames(&server, payload_type, 10);
+    assert_frame_markers(&packets);
+
+    assert_no_error(&pipeline);
+    pipeline.set_state(gst::State::Null).expect("Failed to stop pipeline");
+}
+
+#[test]
+fn h264_frame_marker_test() {
+    frame_marker_test("x264enc tune=zerolatency", 103);
+}
+
+#[test]
+fn h264_nal_aligned_frame_marker_test() {
+    frame_marker_test("x264enc tune=zerolatency ! h264parse ! video/x-h264,alignment=nal", 103);
+}
+
+#[test]
+fn vp8_frame_marker_test() {
+    frame_marker_test("vp8enc deadline=1", 105);
+}
+
+#[test]
+fn vp9_frame_marker_test() {
+    frame_marker_test("vp9enc deadline=1", 107);
+}
+
+#[test]
+fn av1_frame_marker_test() {
+    init();
+    let encoders = ["rav1enc speed-preset=10", "av1enc cpu-used=8"];
+    let encoder = encoders.into_iter().find(|encoder| {
+        gst::ElementFactory::find(encoder.split(' ').next().unwrap()).is_some()
+    });
+    let Some(encoder) = encoder else {
+        eprintln!("Skipping, no AV1 encoder available");
+        return;
+    };
+
+    frame_marker_test(encoder, 101);
+}
+
+#[test]
+fn vp9_stream_test() {
+    init();
+
+    for flexible in [false, true] {
+        let server = voice_server();
+        let pipeline = streaming_pipeline(
+            "videotestsrc num-buffers=10 ! video/x-raw,width=320,height=240 ! videoconvert ! vp9enc deadline=1 ! discordstreamer name=streamer",
+            &server,
+        );
+        let discord_streamer = pipeline.by_name("streamer").unwrap();
+        discord_streamer.set_property("vp9-flexible-mode", flexible);
+
+        pipeline.set_state(gst::State::Playing).expect("Failed to set pipeline state");
+
+        let payloads: Vec<Vec<u8>> = receive_frames(&server, 107, 10).iter().map(|packet| {
+            decrypt_packet(CryptoMode::Lite, &[0; 32], packet).expect("Failed to decrypt packet")
+        }).collect();
+
+        // I and L are always set, F follows the property.
+        assert!(payloads.iter().all(|payload| payload[0] & 0xB0 == if flexible { 0xB0 } else { 0xA0 }));
+        // The first frame is a keyframe carrying the scalability structure.
+        assert_eq!(payloads[0][0] & 0x4A, 0x0A);
+
+        assert_no_error(&pipeline);
+        pipeline.set_state(gst::State::Null).expect("Failed to stop pipeline");
+    }
+}
+
+#[test]
+fn rtp_passthrough_test() {
+    init();
+    let server = voice_server();
+
+    let pipeline = streaming_pipeline(
+        "videotestsrc num-buffers=10 ! videoconvert ! x264enc tune=zerolatency ! rtph264pay pt=96 ssrc=42 ! discordstreamer name=streamer",
+        &server,
+    );
+
+    pipeline.set_state(gst::State::Playing).expect("Failed to set pipeline state");
+
+    // Payload type and SSRC of the upstream payloader are replaced by the Discord ones.
+    let packets = receive_frames(&server, 103, 10);
+    for pair in packets.windows(2) {
+        assert_eq!(u32::from_be_bytes(pair[0][8..12].try_into().unwrap()), 1234);
+        let sequence = |packet: &[u8]| u16::from_be_bytes(packet[2..4].try_into().unwrap());
+        assert_eq!(sequence(&pair[0]).wrapping_add(1), sequence(&pair[1]));
+    }
+
+    assert_no_error(&pipeline);
+    pipeline.set_state(gst::State::Null).expect("Failed to stop pipeline");
+}
+
+#[test]
+fn mtu_test() {
+    init();
+    let server = voice_server();
+
+    let pipeline = streaming_pipeline(
+        "videotestsrc num-buffers=10 pattern=snow ! video/x-raw,width=1280,height=720 ! videoconvert ! x264enc tune=zerolatency ! discordstreamer name=streamer mtu=300",
+        &server,
+    );
+
+    pipeline.set_state(gst::State::Playing).expect("Failed to set pipeline state");
+
+    let packets = receive_frames(&server, 103, 10);
+    assert!(packets.iter().all(|packet| packet.len() <= 300), "Datagram exceeds the MTU");
+    assert_frame_markers(&packets);
+
+    assert_no_error(&pipeline);
+    pipeline.set_state(gst::State::Null).expect("Failed to stop pipeline");
+}
+
+#[test]
+fn mtu_ready_test() {
+    init();
+    let server = voice_server();
+
+    let pipeline = streaming_pipeline(
+        "videotestsrc num-buffers=10 pattern=snow ! video/x-raw,width=1280,height=720 ! videoconvert ! x264enc tune=zerolatency ! discordstreamer name=streamer",
+        &server,
+    );
+
+    // Changes in READY apply when streaming starts.
+    pipeline.set_state(gst::State::Ready).expect("Failed to set pipeline state");
+    pipeline.by_name("streamer").unwrap().set_property("mtu", 300u32);
+    pipeline.set_state(gst::State::Playing).expect("Failed to set pipeline state");
+
+    let packets = receive_frames(&server, 103, 10);
+    assert!(packets.iter().all(|packet| packet.len() <= 300), "Datagram exceeds the MTU");
+
+    assert_no_error(&pipeline);
+    pipeline.set_state(gst::State::Null).expect("Failed to stop pipeline");
+}
+
+#[test]
+fn opus_stream_test() {
+    init();
+    let server = voice_server();
+
+    let pipeline = streaming_pipeline(
+        "discordstreamer name=streamer audiotestsrc num-buffers=30 ! audioconvert ! opusenc frame-size=20 ! streamer.audio_sink",
+        &server,
+    );
+
+    pipeline.set_state(gst::State::Playing).expect("Failed to set pipeline state");
+
+    let packets: Vec<Vec<u8>> = (0..10).map(|_| receive_rtp(&server, 120)).collect();
+    for pair in packets.windows(2) {
+        let sequence = |packet: &[u8]| u16::from_be_bytes([packet[2], packet[3]]);
+        assert_eq!(sequence(&pair[1]), sequence(&pair[0]).wrapping_add(1));
+        // 20 ms at 48 kHz.
+        assert_eq!(timestamp(&pair[1]).wrapping_sub(timestamp(&pair[0])), 960);
+    }
+    for packet in &packets {
+        assert_eq!(packet[8..12], 1235u32.to_be_bytes(), "Not sent with the audio SSRC");
+        let payload = decrypt_packet(CryptoMode::Lite, &[0; 32], packet).expect("Failed to decrypt packet");
+        assert!(!payload.is_empty());
+    }
+
+    assert_no_error(&pipeline);
+    pipeline.set_state(gst::State::Null).expect("Failed to stop pipeline");
+}
+
+#[test]
+fn opus_mtu_test() {
+    init();
+    let server = voice_server();
+
+    // Opus frames are never split, at this bitrate they exceed the smallest MTU.
+    let pipeline = streaming_pipeline(
+        "discordstreamer name=streamer mtu=128 audiotestsrc num-buffers=30 wave=white-noise ! audioconvert ! opusenc bitrate=256000 bitrate-type=cbr ! streamer.audio_sink",
+        &server,
+    );
+
+    pipeline.set_state(gst::State::Playing).expect("Failed to set pipeline state");
+
+    let message = pipeline.bus().unwrap()
+        .timed_pop_filtered(gst::ClockTime::from_seconds(5), &[gst::MessageType::Warning])
+        .expect("No warning posted");
+    let gst::MessageView::Warning(message) = message.view() else {
+        unreachable!();
+    };
+    assert!(message.error().to_string().contains("exceeding the MTU"), "{}", message.error());
+
+    pipeline.set_state(gst::State::Null).expect("Failed to stop pipeline");
+}
+
+/// Streams 25 fps H.264 in NAL units and 20 ms Opus frames, returning the RTP
+/// timestamps of the first ten video frames, one per packet, and of the first ten
+/// audio packets.
+fn stream_timestamps() -> (Vec<Vec<u32>>, Vec<u32>) {
+    let server = voice_server();
+    let pipeline = streaming_pipeline(
+        "videotestsrc num-buffers=20 ! video/x-raw,width=320,height=240,framerate=25/1 ! videoconvert ! x264enc tune=zerolatency ! h264parse ! video/x-h264,alignment=nal ! discordstreamer name=streamer \
+         audiotestsrc num-buffers=30 ! audioconvert ! opusenc frame-size=20 ! streamer.audio_sink",
+        &server,
+    );
+
+    pipeline.set_state(gst::State::Playing).expect("Failed to set pipeline state");
+
+    let mut frames = vec![Vec::new()];
+    let mut audio = Vec::new();
+    while frames.len() <= 10 || audio.len() < 10 {
+        let packet = server.recv();
+        match packet[1] & 0x7F {
+            103 if frames.len() <= 10 => {
+                frames.last_mut().unwrap().push(timestamp(&packet));
+                if marker(&packet) {
+                    frames.push(Vec::new());
+                }
+            }
+            120 if audio.len() < 10 => audio.push(timestamp(&packet)),
+            _ => (),
+        }
+    }
+    frames.pop();
+
+    assert_no_error(&pipeline);
+    pipeline.set_state(gst::State::Null).expect("Failed to stop pipeline");
+
+    (frames, audio)
+}
+
+#[test]
+fn rtp_timestamp_test() {
+    init();
+
+    let (frames, audio) = stream_timestamps();
+
+    // Parameter sets and slices of a frame share its timestamp.
+    for frame in &frames {
+        assert!(frame.iter().all(|&timestamp| timestamp == frame[0]), "Frame with timestamps {:?}", frame);
+    }
+    // 40 ms at 90 kHz.
+    for pair in frames.windows(2) {
+        assert_eq!(pair[1][0].wrapping_sub(pair[0][0]), 3600);
+    }
+    // 20 ms at 48 kHz.
+    for pair in audio.windows(2) {
+        assert_eq!(pair[1].wrapping_sub(pair[0]), 960);
+    }
+
+    // Each SSRC starts from its own random base.
+    let (next_frames, next_audio) = stream_timestamps();
+    assert_ne!(frames[0][0], audio[0]);
+    assert_ne!(frames[0][0], next_frames[0][0]);
+    assert_ne!(audio[0], next_audio[0]);
+}
+
+#[test]
+fn aes256_gcm_rtpsize_test() {
+    use aes_gcm::aead::{AeadInPlace, KeyInit};
+    use aes_gcm::{Aes256Gcm, Nonce, Tag};
+
+    init();
+    let server = voice_server();
+
+    let pipeline = streaming_pipeline(
+        "videotestsrc num-buffers=10 ! videoconvert ! x264enc tune=zerolatency ! discordstreamer name=streamer crypto-mode=aead_aes256_gcm_rtpsize",
+        &server,
+    );
+
+    pipeline.set_state(gst::State::Playing).expect("Failed to set pipeline state");
+
+    // The header is authenticated, the tag and the 4 byte nonce counter follow the payload.
+    let cipher = Aes256Gcm::new_from_slice(&[0; 32]).unwrap();
+    let packets = receive_frames(&server, 103, 10);
+    for packet in &packets {
+        let (header, body) = packet.split_at(12);
+        let (body, counter) = body.split_at(body.len() - 4);
+        let (ciphertext, tag) = body.split_at(body.len() - 16);
+
+        let mut nonce = Nonce::default();
+        nonce[..4].copy_from_slice(counter);
+        let mut payload = ciphertext.to_vec();
+        cipher
+            .decrypt_in_place_detached(&nonce, header, &mut payload, Tag::from_slice(tag))
+            .expect("Failed to decrypt packet");
+    }
+
+    let counter = |packet: &[u8]| u32::from_be_bytes(packet[packet.len() - 4..].try_into().unwrap());
+    for pair in packets.windows(2) {
+        assert_eq!(counter(&pair[0]).wrapping_add(1), counter(&pair[1]));
+    }
+
+    assert_no_error(&pipeline);
+    pipeline.set_state(gst::State::Null).expect("Failed to stop pipeline");
+}
+
+// Vectors computed with an independent XChaCha20-Poly1305 implementation, checked against
+// the test vectors of draft-irtf-cfrg-xchacha.
+#[test]
+fn xchacha20_poly1305_rtpsize_vector_test() {
+    let key: Vec<u8> = (0..32).collect();
+    let mut state = CryptoState::XChaCha20Poly1305(Wrapping(5));
+
+    let header = hex("80670001000000640000002a");
+    let packet = encrypt_packet(&mut state, &key, &header, b"discordstreamer");
+    assert_eq!(
+        packet,
+        hex("80670001000000640000002ab08bcb51477c1ecd893fc5eae061dce07fb9031e4152b04fb30e2376653c4400000005"),
+    );
+}
+
+#[test]
+fn xchacha20_poly1305_rtpsize_extension_vector_test() {
+    let key: Vec<u8> = (0..32).collect();
+    let mut state = CryptoState::XChaCha20Poly1305(Wrapping(5));
+
+    // The extension preamble stays in the clear, the extension data is encrypted.
+    let header = hex("90670002000000640000002a");
+    let mut payload = hex("bede000110ff0000");
+    payload.extend_from_slice(b"discordstreamer");
+    let packet = encrypt_packet(&mut state, &key, &header, &payload);
+    assert_eq!(
+        packet,
+        hex("90670002000000640000002abede0001c41db8324c6709dd923fc4f8f976cb2d0c74b9c624bb62002d55720b5aa6ee7e41e97a00000005"),
+    );
+}
+
+#[test]
+fn xchacha20_poly1305_rtpsize_mode_name_test() {
+    assert_eq!(
+        serde_plain::from_str::<CryptoMode>("aead_xchacha20_poly1305_rtpsize").unwrap(),
+        CryptoMode::XChaCha20Poly1305,
+    );
+}
+
+#[test]
+fn crypto_round_trip_test() {
+    let key = [7u8; 32];
+    let header = hex("80670001000000640000002a");
+
+    for mode in ALL_CRYPTO_MODES {
+        for payload in [&b""[..], b"x", b"discordstreamer", &[0xAB; 1000]] {
+            let packet = encrypt_packet(&mut CryptoState::from(mode), &key, &header, payload);
+            assert_eq!(&packet[..12], &header[..], "{:?} modified the header", mode);
+            assert_eq!(packet.len(), header.len() + payload.len() + mode.payload_overhead());
+            assert_eq!(decrypt_packet(mode, &key, &packet).unwrap(), payload, "{:?} round trip failed", mode);
+        }
+    }
+}
+
+#[test]
+fn crypto_round_trip_extension_test() {
+    let key = [7u8; 32];
+    let header = hex("90670002000000640000002a");
+    let mut payload = hex("bede000110ff0000");
+    payload.extend_from_slice(b"discordstreamer");
+
+    for mode in ALL_CRYPTO_MODES {
+        let packet = encrypt_packet(&mut CryptoState::from(mode), &key, &header, &payload);
+        assert_eq!(decrypt_packet(mode, &key, &packet).unwrap(), payload, "{:?} round trip failed", mode);
+    }
+}
+
+#[test]
+fn crypto_bad_tag_test() {
+    let key = [7u8; 32];
+    let header = hex("90670002000000640000002a");
+    let mut payload = hex("bede000110ff0000");
+    payload.extend_from_slice(b"discordstreamer");
+
+    for mode in ALL_CRYPTO_MODES {
+        let packet = encrypt_packet(&mut CryptoState::from(mode), &key, &header, &payload);
+
+        // The XSalsa20 suffix modes leave the header unauthenticated, the Normal mode
+        // uses it as the nonce and the rtpsize modes pass it as associated data.
+        let authenticated_header = !matches!(mode, CryptoMode::Suffix | CryptoMode::Lite);
+        let start = if authenticated_header { 0 } else { header.len() };
+        for index in start..packet.len() {
+            let mut tampered = packet.clone();
+            tampered[index] ^= 0x01;
+            assert!(decrypt_packet(mode, &key, &tampered).is_err(), "{:?} accepted a modified byte {}", mode, index);
+        }
+
+        assert_eq!(decrypt_packet(mode, &[8u8; 32], &packet), Err(DecryptError::BadTag));
+    }
+}
+
+#[test]
+fn crypto_short_packet_test() {
+    let key = [7u8; 32];
+    let header = hex("80670001000000640000002a");
+
+    for mode in ALL_CRYPTO_MODES {
+        let packet = encrypt_packet(&mut CryptoState::from(mode), &key, &header, b"discordstreamer");
+
+        if mode != CryptoMode::Normal {
+            let truncated = &packet[..header.len() + mode.nonce_size() - 1];
+            assert_eq!(decrypt_packet(mode, &key, truncated), Err(DecryptError::MissingNonce), "{:?}", mode);
+        }
+
+        let nonce_only = [&header[..], &packet[packet.len() - mode.nonce_size()..]].concat();
+        let truncated = if mode == CryptoMode::Normal { &header[..] } else { &nonce_only[..] };
+        assert_eq!(decrypt_packet(mode, &key, truncated), Err(DecryptError::ShortPacket), "{:?}", mode);
+    }
+}
+
+#[test]
+fn crypto_mismatched_cipher_test() {
+    let key = [7u8; 32];
+    let header = hex("80670001000000640000002a");
+    let mut packet = encrypt_packet(&mut CryptoState::from(CryptoMode::Lite), &key, &header, b"discordstreamer");
+
+    let mut rtp = MutableRtpPacket::new(&mut packet[..]).unwrap();
+    let cipher = Cipher::new(CryptoMode::Aes256Gcm, &key).unwrap();
+    assert_eq!(CryptoMode::Lite.decrypt_in_place(&mut rtp, &cipher), Err(DecryptError::MismatchedCipher));
+}
+
+#[test]
+fn encrypted_stream_test() {
+    init();
+    let server = voice_server();
+
+    let pipeline = streaming_pipeline(
+        "videotestsrc num-buffers=10 ! videoconvert ! x264enc tune=zerolatency ! discordstreamer name=streamer",
+        &server,
+    );
+
+    pipeline.set_state(gst::State::Playing).expect("Failed to set pipeline state");
+
+    for packet in receive_frames(&server, 103, 10) {
+        let payload = decrypt_packet(CryptoMode::Lite, &[0; 32], &packet).expect("Failed to decrypt packet");
+        assert!(!payload.is_empty());
+    }
+
+    assert_no_error(&pipeline);
+    pipeline.set_state(gst::State::Null).expect("Failed to stop pipeline");
+}
+
+#[test]
+fn crypto_key_parsing_test() {
+    let key: Vec<u8> = (0..32).collect();
+
+    for text in [
+        "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+        "000102030405060708090A0B0C0D0E0F101112131415161718191A1B1C1D1E1F",
+        "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=",
+        "[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31]",
+        " [0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20,21,22,23,24,25,26,27,28,29,30,31]\n",
+    ] {
+        assert_eq!(*crypto::parse_key(text).expect(text), key, "{}", text);
+    }
+
+    assert_eq!(crypto::parse_key("00010203").unwrap_err(), KeyError::WrongLength(4));
+    assert_eq!(crypto::parse_key("").unwrap_err(), KeyError::WrongLength(0));
+    assert_eq!(crypto::parse_key("[0, 1, 2]").unwrap_err(), KeyError::WrongLength(3));
+    assert_eq!(crypto::parse_key("[0, 256]").unwrap_err(), KeyError::InvalidByteArray);
+    assert_eq!(crypto::parse_key("[0, -1]").unwrap_err(), KeyError::InvalidByteArray);
+    assert_eq!(crypto::parse_key("[0, 1").unwrap_err(), KeyError::InvalidByteArray);
+    assert_eq!(crypto::parse_key("not a key!").unwrap_err(), KeyError::UnknownEncoding);
+    assert_eq!(crypto::parse_key("0001020").unwrap_err(), KeyError::UnknownEncoding);
+
+    assert_eq!(crypto::validate_key(&key), Ok(()));
+    assert_eq!(crypto::validate_key(&key[1..]), Err(KeyError::WrongLength(31)));
+}
+
+#[test]
+fn crypto_key_string_test() {
+    init();
+    let server = voice_server();
+
+    let pipeline = streaming_pipeline(
+        "videotestsrc num-buffers=10 ! videoconvert ! x264enc tune=zerolatency ! discordstreamer name=streamer",
+        &server,
+    );
+
+    let key: Vec<u8> = (0..32).collect();
+    let discord_streamer = pipeline.by_name("streamer").unwrap();
+    discord_streamer.set_property("crypto-key-string", "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=".to_value());
+    // Invalid keys are reported and ignored, leaving the last valid one in place.
+    discord_streamer.set_property("crypto-key-string", "[1, 2, 3]".to_value());
+
+    let message = pipeline.bus().unwrap().pop_filtered(&[gst::MessageType::Warning]).expect("No warning posted");
+    let gst::MessageView::Warning(message) = message.view() else {
+        unreachable!();
+    };
+    assert!(message.error().to_string().contains("got 3 bytes"), "{}", message.error());
+
+    pipeline.set_state(gst::State::Playing).expect("Failed to set pipeline state");
+
+    for packet in receive_frames(&server, 103, 10) {
+        decrypt_packet(CryptoMode::Lite, &key, &packet).expect("Failed to decrypt packet");
+    }
+
+    assert_no_error(&pipeline);
+    pipeline.set_state(gst::State::Null).expect("Failed to stop pipeline");
+}
+
+/// Pipeline whose streamer only needs to reach the READY state.
+fn discovery_pipeline(server_address: SocketAddr) -> (gst::Pipeline, gst::Element) {
+    let pipeline = gst::parse_launch("videotestsrc ! videoconvert ! x264enc ! discordstreamer name=streamer")
+        .expect("Failed to parse pipeline")
+        .downcast::<gst::Pipeline>()
+        .expect("Not a pipeline");
+
+    let discord_streamer = pipeline.by_name("streamer").unwrap();
+    discord_streamer.set_property("crypto-key", glib::Bytes::from_static(&[0; 32]).to_value());
+    discord_streamer.set_property("address", server_address.to_string().to_value());
+    discord_streamer.set_property("video-ssrc", 1234u32.to_value());
+    discord_streamer.set_property("audio-ssrc", 1235u32.to_value());
+
+    (pipeline, discord_streamer)
+}
+
+#[test]
+fn ip_discovery_test() {
+    init();
+    let server = voice_server();
+    let (pipeline, discord_streamer) = discovery_pipeline(server.local_addr());
+
+    assert_eq!(discord_streamer.property::<Option<String>>("external-ip"), None);
+    assert_eq!(discord_streamer.property::<u32>("external-port"), 0);
+
+    pipeline.set_state(gst::State::Ready).expect("Failed to set pipeline state");
+
+    let external_address: SocketAddr = EXTERNAL_ADDRESS.parse().unwrap();
+    assert_eq!(discord_streamer.property::<Option<String>>("external-ip"), Some(external_address.ip().to_string()));
+    assert_eq!(discord_streamer.property::<u32>("external-port"), u32::from(external_address.port()));
+
+    let bus = pipeline.bus().unwrap();
+    let message = bus.pop_filtered(&[gst::MessageType::Element]).expect("No IP discovery message");
+    let structure = message.structure().unwrap();
+    assert_eq!(structure.name(), "discord-ip-discovery");
+    assert_eq!(structure.get::<String>("ip").unwrap(), external_address.ip().to_string());
+    assert_eq!(structure.get::<u32>("port").unwrap(), u32::from(external_address.port()));
+
+    assert_no_error(&pipeline);
+    pipeline.set_state(gst::State::Null).expect("Failed to stop pipeline");
+    assert_eq!(discord_streamer.property::<Option<String>>("external-ip"), None);
+}
+
+#[test]
+fn ip_discovery_retry_test() {
+    init();
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let (pipeline, discord_streamer) = discovery_pipeline(socket.local_addr().unwrap());
+
+    let responder = thread::spawn(move || {
+        let mut buf = [0u8; 2048];
+        let external_address = "[2001:db8::7]:50004".parse().unwrap();
+
+        // The first request is lost.
+        let (len, _) = socket.recv_from(&mut buf).unwrap();
+        assert_eq!(discovery_request_ssrc(&buf[..len]), Some(1235));
+
+        // Responses for other SSRCs and unrelated datagrams are skipped.
+        let (len, peer) = socket.recv_from(&mut buf).unwrap();
+        assert_eq!(discovery_request_ssrc(&buf[..len]), Some(1235));
+        socket.send_to(&discovery_packet(2, 1, "198.51.100.1:1".parse().unwrap()), peer).unwrap();
+        socket.send_to(b"not a discovery response", peer).unwrap();
+        socket.send_to(&discovery_packet(2, 1235, external_address), peer).unwrap();
+    });
+
+    pipeline.set_state(gst::State::Ready).expect("Failed to set pipeline state");
+    responder.join().unwrap();
+
+    assert_eq!(discord_streamer.property::<Option<String>>("external-ip").as_deref(), Some("2001:db8::7"));
+    assert_eq!(discord_streamer.property::<u32>("external-port"), 50004);
+
+    pipeline.set_state(gst::State::Null).expect("Failed to stop pipeline");
+}
+
+#[test]
+fn ip_discovery_timeout_test() {
+    init();
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let (pipeline, _) = discovery_pipeline(socket.local_addr().unwrap());
+
+    assert!(pipeline.set_state(gst::State::Ready).is_err(), "State change succeeded without IP discovery");
+
+    let bus = pipeline.bus().unwrap();
+    let message = bus.pop_filtered(&[gst::MessageType::Error]).expect("No error posted");
+    let gst::MessageView::Error(error) = message.view() else {
+        unreachable!();
+    };
+    assert!(error.error().to_string().contains("IP discovery"), "{}", error.error());
+
+    // Every attempt sent a request.
+    socket.set_nonblocking(true).unwrap();
+    let mut buf = [0u8; 2048];
+    let mut requests = 0;
+    while let Ok(len) = socket.recv(&mut buf) {
+        assert_eq!(discovery_request_ssrc(&buf[..len]), Some(1235));
+        requests += 1;
+    }
+    assert_eq!(requests, 3);
+
+    pipeline.set_state(gst::State::Null).expect("Failed to stop pipeline");
+}
+
+#[test]
+fn ip_discovery_unlocked_props_test() {
+    init();
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let (pipeline, discord_streamer) = discovery_pipeline(socket.local_addr().unwrap());
+
+    let state_change = thread::spawn({
+        let pipeline = pipeline.clone();
+        move || pipeline.set_state(gst::State::Ready)
+    });
+    // Wait for the first request, the server never answers.
+    let mut buf = [0u8; 2048];
+    socket.recv(&mut buf).unwrap();
+
+    // Setting properties does not wait for IP discovery to give up.
+    let start = Instant::now();
+    discord_streamer.set_property("vp9-flexible-mode", true);
+    assert!(start.elapsed() < Duration::from_millis(500), "Blocked for {:?}", start.elapsed());
+
+    assert!(state_change.join().unwrap().is_err());
+    pipeline.set_state(gst::State::Null).expect("Failed to stop pipeline");
+}
+
+#[test]
+fn server_traffic_test() {
+    init();
+    let server = voice_server();
+
+    let pipeline = streaming_pipeline(
+        "videotestsrc is-live=true ! videoconvert ! x264enc tune=zerolatency ! discordstreamer name=streamer",
+        &server,
+    );
+
+    for _ in 0..2 {
+        pipeline.set_state(gst::State::Playing).expect("Failed to set pipeline state");
+        receive_frames(&server, 103, 2);
+
+        // Whatever the server sends back is received and dropped without disturbing the stream.
+        let external_address = EXTERNAL_ADDRESS.parse().unwrap();
+        let mut receiver_report = vec![0x81, 201, 0, 7, 0, 0, 0x04, 0xd2];
+        receiver_report.extend_from_slice(&[0; 24]);
+        let mut foreign_rtp = vec![0x80, 120, 0, 1, 0, 0, 0, 1, 0, 0, 0x30, 0x39];
+        foreign_rtp.extend_from_slice(&[0xAB; 40]);
+        for packet in [
+            receiver_report,
+            foreign_rtp,
+            discovery_packet(2, 1235, external_address),
+            b"unknown".to_vec(),
+            vec![0; 8],
+        ] {
+            server.send(&packet);
+        }
+
+        receive_frames(&server, 103, 5);
+        assert_no_error(&pipeline);
+
+        // Stopping the receive thread does not hold up the state change.
+        let start = Instant::now();
+        pipeline.set_state(gst::State::Null).expect("Failed to stop pipeline");
+        assert!(start.elapsed() < Duration::from_secs(1), "Stopping took {:?}", start.elapsed());
+    }
+}
+
+#[test]
+fn keepalive_test() {
+    init();
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let (pipeline, discord_streamer) = discovery_pipeline(socket.local_addr().unwrap());
+    discord_streamer.set_property("keepalive-interval", 50u32);
+
+    let server = thread::spawn(move || {
+        let mut buf = [0u8; 2048];
+        let (len, peer) = socket.recv_from(&mut buf).unwrap();
+        let ssrc = discovery_request_ssrc(&buf[..len]).unwrap();
+        socket.send_to(&discovery_packet(2, ssrc, EXTERNAL_ADDRESS.parse().unwrap()), peer).unwrap();
+
+        // Echo the first keepalives, then let the path die.
+        for expected in 0..10u32 {
+            let (len, _) = socket.recv_from(&mut buf).unwrap();
+            assert_eq!(keepalive_counter(&buf[..len]), Some(expected));
+            if expected < 3 {
+                socket.send_to(&buf[..len], peer).unwrap();
+            }
+        }
+    });
+
+    pipeline.set_state(gst::State::Ready).expect("Failed to set pipeline state");
+
+    let bus = pipeline.bus().unwrap();
+    let message = bus
+        .timed_pop_filtered(gst::ClockTime::from_seconds(5), &[gst::MessageType::Warning])
+        .expect("No warning about the dead path");
+    let gst::MessageView::Warning(warning) = message.view() else {
+        unreachable!();
+    };
+    assert!(warning.error().to_string().contains("keepalives"), "{}", warning.error());
+
+    server.join().unwrap();
+    pipeline.set_state(gst::State::Null).expect("Failed to stop pipeline");
+}
+
+#[test]
+fn keepalive_disabled_test() {
+    init();
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+    let (pipeline, discord_streamer) = discovery_pipeline(socket.local_addr().unwrap());
+    discord_streamer.set_property("keepalive-interval", 0u32);
+
+    let server = thread::spawn(move || {
+        let mut buf = [0u8; 2048];
+        let (len, peer) = socket.recv_from(&mut buf).unwrap();
+        let ssrc = discovery_request_ssrc(&buf[..len]).unwrap();
+        socket.send_to(&discovery_packet(2, ssrc, EXTERNAL_ADDRESS.parse().unwrap()), peer).unwrap();
+        socket.recv_from(&mut buf).is_err()
+    });
+
+    pipeline.set_state(gst::State::Ready).expect("Failed to set pipeline state");
+    assert!(server.join().unwrap(), "Keepalive sent while disabled");
+    pipeline.set_state(gst::State::Null).expect("Failed to stop pipeline");
+}
+
+#[test]
+fn keepalive_ready_test() {
+    init();
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let (pipeline, discord_streamer) = discovery_pipeline(socket.local_addr().unwrap());
+    discord_streamer.set_property("keepalive-interval", 0u32);
+
+    let server = thread::spawn(move || {
+        let mut buf = [0u8; 2048];
+        let (len, peer) = socket.recv_from(&mut buf).unwrap();
+        let ssrc = discovery_request_ssrc(&buf[..len]).unwrap();
+        socket.send_to(&discovery_packet(2, ssrc, EXTERNAL_ADDRESS.parse().unwrap()), peer).unwrap();
+
+        // Skip the preroll frame.
+        loop {
+            let (len, _) = socket.recv_from(&mut buf).expect("No keepalive received");
+            if let Some(counter) = keepalive_counter(&buf[..len]) {
+                return counter;
+            }
+        }
+    });
+
+    // Changes in READY apply when streaming starts.
+    pipeline.set_state(gst::State::Ready).expect("Failed to set pipeline state");
+    discord_streamer.set_property("keepalive-interval", 50u32);
+    pipeline.set_state(gst::State::Paused).expect("Failed to set pipeline state");
+
+    assert_eq!(server.join().unwrap(), 0);
+    assert_no_error(&pipeline);
+    pipeline.set_state(gst::State::Null).expect("Failed to stop pipeline");
+}
+
+#[test]
+fn bind_address_test() {
+    init();
+    let server = voice_server();
+    let (pipeline, discord_streamer) = discovery_pipeline(server.local_addr());
+
+    let bind_port = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    // Names may resolve to both families, the bind address picks the IPv4 one.
+    discord_streamer.set_property("address", format!("localhost:{}", server.local_addr().port()));
+    discord_streamer.set_property("bind-address", "127.0.0.1");
+    discord_streamer.set_property("bind-port", u32::from(bind_port));
+
+    pipeline.set_state(gst::State::Ready).expect("Failed to set pipeline state");
+    assert_eq!(server.client_addr(), Some(SocketAddr::from(([127, 0, 0, 1], bind_port))));
+
+    assert_no_error(&pipeline);
+    pipeline.set_state(gst::State::Null).expect("Failed to stop pipeline");
+}
+
+#[test]
+fn bind_address_error_test() {
+    init();
+    let server = voice_server();
+
+    for (bind_address, error) in [("localhost", "no IP address"), ("::1", "IP family")] {
+        let (pipeline, discord_streamer) = discovery_pipeline(server.local_addr());
+        discord_streamer.set_property("bind-address", bind_address);
+
+        assert!(pipeline.set_state(gst::State::Ready).is_err(), "Bound to {}", bind_address);
+        let message = pipeline.bus().unwrap().pop_filtered(&[gst::MessageType::Error]).expect("No error posted");
+        let gst::MessageView::Error(message) = message.view() else {
+            unreachable!();
+        };
+        assert!(message.error().to_string().contains(error), "{}", message.error());
+
+        pipeline.set_state(gst::State::Null).expect("Failed to stop pipeline");
+    }
+}
+
+#[test]
+fn provided_socket_test() {
+    init();
+    let server = voice_server();
+    let pipeline = streaming_pipeline(
+        "videotestsrc num-buffers=10 ! videoconvert ! x264enc tune=zerolatency ! discordstreamer name=streamer",
+        &server,
+    );
+    let discord_streamer = pipeline.by_name("streamer").unwrap();
+
+    // The application already talks to the server over its own connected socket.
+    let udp_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    udp_socket.connect(server.local_addr()).unwrap();
+    let socket = unsafe { gio::Socket::from_fd(udp_socket) }.expect("Failed to wrap socket");
+    discord_streamer.set_property("address", None::<String>);
+    discord_streamer.set_property("socket", &socket);
+
+    pipeline.set_state(gst::State::Playing).expect("Failed to set pipeline state");
+
+    for packet in receive_frames(&server, 103, 10) {
+        decrypt_packet(CryptoMode::Lite, &[0; 32], &packet).expect("Failed to decrypt packet");
+    }
+    // The application did IP discovery over its socket already.
+    assert_eq!(server.client_addr(), None, "IP discovery repeated over the provided socket");
+    assert_eq!(discord_streamer.property::<Option<String>>("external-ip"), None);
+
+    assert_no_error(&pipeline);
+    pipeline.set_state(gst::State::Null).expect("Failed to stop pipeline");
+    assert!(socket.is_closed());
+}
+
+/// Blocking mode and read timeout of the descriptor behind `socket`.
+fn socket_options(socket: &impl std::os::unix::io::AsRawFd) -> (bool, Option<Duration>) {
+    use std::os::unix::io::BorrowedFd;
+
+    let fd = socket.as_raw_fd();
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    assert!(flags >= 0, "Failed to read descriptor flags: {}", std::io::Error::last_os_error());
+    let duplicate = UdpSocket::from(unsafe { BorrowedFd::borrow_raw(fd) }.try_clone_to_owned().unwrap());
+    (flags & libc::O_NONBLOCK != 0, duplicate.read_timeout().unwrap())
+}
+
+#[test]
+fn close_socket_test() {
+    init();
+    let server = voice_server();
+    let (pipeline, discord_streamer) = discovery_pipeline(server.local_addr());
+
+    let udp_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let socket = unsafe { gio::Socket::from_fd(udp_socket.try_clone().unwrap()) }.expect("Failed to wrap socket");
+    discord_streamer.set_property("socket", &socket);
+    discord_streamer.set_property("close-socket", false);
+    // Ignored in favour of the provided socket.
+    discord_streamer.set_property("bind-port", 1u32);
+
+    // GIO sockets are non-blocking.
+    let options = socket_options(&socket);
+    assert_eq!(options, (true, None));
+    // The socket is connected to address and stays usable across restarts.
+    for _ in 0..2 {
+        pipeline.set_state(gst::State::Ready).expect("Failed to set pipeline state");
+        assert_eq!(udp_socket.peer_addr().unwrap(), server.local_addr());
+        assert_eq!(server.client_addr(), None, "IP discovery repeated over the provided socket");
+
+        pipeline.set_state(gst::State::Null).expect("Failed to stop pipeline");
+        assert!(!socket.is_closed());
+        assert_eq!(socket_options(&socket), options, "Socket options not restored");
+    }
+
+    assert_no_error(&pipeline);
+}
+
+#[test]
+fn socket_fd_test() {
+    use std::os::unix::io::{BorrowedFd, IntoRawFd};
+
+    init();
+    let server = voice_server();
+    let pipeline = streaming_pipeline(
+        "videotestsrc num-buffers=10 ! videoconvert ! x264enc tune=zerolatency ! discordstreamer name=streamer",
+        &server,
+    );
+    let discord_streamer = pipeline.by_name("streamer").unwrap();
+
+    let udp_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    udp_socket.connect(server.local_addr()).unwrap();
+    // Closed by the element at the end.
+    let fd = udp_socket.into_raw_fd();
+    discord_streamer.set_property("address", None::<String>);
+    discord_streamer.set_property("socket-fd", fd);
+    discord_streamer.set_property("close-socket", false);
+
+    pipeline.set_state(gst::State::Playing).expect("Failed to set pipeline state");
+    for packet in receive_frames(&server, 103, 10) {
+        decrypt_packet(CryptoMode::Lite, &[0; 32], &packet).expect("Failed to decrypt packet");
+    }
+    assert_no_error(&pipeline);
+    pipeline.set_state(gst::State::Null).expect("Failed to stop pipeline");
+
+    // Still open and blocking, like it was handed over.
+    assert_eq!(socket_options(&unsafe { BorrowedFd::borrow_raw(fd) }), (false, None));
+
+    discord_streamer.set_property("close-socket", true);
+    pipeline.set_state(gst::State::Ready).expect("Failed to set pipeline state");
+    pipeline.set_state(gst::State::Null).expect("Failed to stop pipeline");
+    assert_eq!(discord_streamer.property::<i32>("socket-fd"), -1);
+}
+
+#[test]
+fn unconnected_socket_error_test() {
+    init();
+    let server = voice_server();
+    let (pipeline, discord_streamer) = discovery_pipeline(server.local_addr());
+
+    let socket = unsafe { gio::Socket::from_fd(UdpSocket::bind("127.0.0.1:0").unwrap()) }.expect("Failed to wrap socket");
+    discord_streamer.set_property("address", None::<String>);
+    discord_streamer.set_property("socket", &socket);
+
+    assert!(pipeline.set_state(gst::State::Ready).is_err());
+    let message = pipeline.bus().unwrap().pop_filtered(&[gst::MessageType::Error]).expect("No error posted");
+    let gst::MessageView::Error(message) = message.view() else {
+        unreachable!();
+    };
+    assert!(message.error().to_string().contains("not connected"), "{}", message.error());
+
+    // Closed even though READY was never reached.
+    assert!(socket.is_closed());
+    pipeline.set_state(gst::State::Null).expect("Failed to stop pipeline");
+}
+
+#[test]
+fn ipv6_stream_test() {
+    init();
+    if UdpSocket::bind("[::1]:0").is_err() {
+        eprintln!("Skipping, IPv6 loopback unavailable");
+        return;
+    }
+    let server = voice_server_at("[::1]:0");
+
+    let pipeline = streaming_pipeline(
+        "videotestsrc num-buffers=10 ! videoconvert ! x264enc tune=zerolatency ! discordstreamer name=streamer",
+        &server,
+    );
+
+    pipeline.set_state(gst::State::Playing).expect("Failed to set pipeline state");
+
+    for packet in receive_frames(&server, 103, 10) {
+        decrypt_packet(CryptoMode::Lite, &[0; 32], &packet).expect("Failed to decrypt packet");
+    }
+    assert!(server.client_addr().unwrap().is_ipv6());
+
+    assert_no_error(&pipeline);
+    pipeline.set_state(gst::State::Null).expect("Failed to stop pipeline");
+}
+
+/// Returns the 4 byte counter nonce of a packet encrypted in a counter based mode.
+fn counter_nonce(packet: &[u8]) -> u32 {
+    u32::from_be_bytes(packet[packet.len() - 4..].try_into().unwrap())
+}
+
+#[test]
+fn nonce_counter_test() {
+    let key = [7u8; 32];
+    let header = hex("80670001000000640000002a");
+
+    for mode in [CryptoMode::Lite, CryptoMode::Aes256Gcm, CryptoMode::XChaCha20Poly1305] {
+        let mut state = CryptoState::from(mode);
+        let first = counter_nonce(&encrypt_packet(&mut state, &key, &header, b"discordstreamer"));
+        for i in 1..1000u32 {
+            let nonce = counter_nonce(&encrypt_packet(&mut state, &key, &header, b"discordstreamer"));
+            assert_eq!(nonce, first.wrapping_add(i), "{:?} counter did not advance", mode);
+        }
+    }
+}
+
+#[test]
+fn nonce_uniqueness_test() {
+    let key = [7u8; 32];
+    let header = hex("80670001000000640000002a");
+
+    for mode in [CryptoMode::Suffix, CryptoMode::Lite, CryptoMode::Aes256Gcm, CryptoMode::XChaCha20Poly1305] {
+        let mut state = CryptoState::from(mode);
+        let mut detector = NonceReuseDetector::default();
+        let mut nonces = HashSet::new();
+        for _ in 0..10_000 {
+            let packet = encrypt_packet(&mut state, &key, &header, b"discordstreamer");
+            assert!(detector.check(mode, &packet), "{:?} reused a nonce", mode);
+            nonces.insert(packet[packet.len() - mode.nonce_size()..].to_vec());
+        }
+        assert_eq!(nonces.len(), 10_000, "{:?} reused a nonce", mode);
+    }
+}
+
+#[test]
+fn nonce_reuse_detector_test() {
+    let key = [7u8; 32];
+    let header = hex("80670001000000640000002a");
+
+    for mode in ALL_CRYPTO_MODES {
+        let mut detector = NonceReuseDetector::default();
+        let packet = encrypt_packet(&mut CryptoState::from(mode), &key, &header, b"discordstreamer");
+        assert!(detector.check(mode, &packet));
+        assert!(!detector.check(mode, &packet), "{:?} reuse not detected", mode);
+    }
+
+    // A counter which is not stored back repeats the same nonce for every packet.
+    let mut detector = NonceReuseDetector::default();
+    let first = encrypt_packet(&mut CryptoState::Lite(Wrapping(42)), &key, &header, b"discordstreamer");
+    let second = encrypt_packet(&mut CryptoState::Lite(Wrapping(42)), &key, &header, b"discordstreamer");
+    assert!(detector.check(CryptoMode::Lite, &first));
+    assert!(!detector.check(CryptoMode::Lite, &second));
+}
+
+#[test]
+fn stream_nonce_uniqueness_test() {
+    init();
+    let server = voice_server();
+
+    // Audio and video share the key and therefore the nonce counter.
+    let pipeline = streaming_pipeline(
+        "discordstreamer name=streamer \
+         videotestsrc num-buffers=30 ! videoconvert ! x264enc tune=zerolatency ! streamer.video_sink \
+         audiotestsrc num-buffers=30 ! audioconvert ! opusenc ! streamer.audio_sink",
+        &server,
+    );
+
+    pipeline.set_state(gst::State::Playing).expect("Failed to set pipeline state");
+
+    let mut nonces = HashSet::new();
+    let (mut video, mut audio) = (0, 0);
+    while video < 20 || audio < 20 {
+        let packet = &server.recv()[..];
+        if packet.len() < 12 || packet[0] >> 6 != 2 {
+            continue;
+        }
+        match packet[1] & 0x7F {
+            103 => video += 1,
+            120 => audio += 1,
+            _ => continue,
+        }
+        assert!(nonces.insert(counter_nonce(packet)), "Nonce reused across streams");
+    }
+
+    assert_no_error(&pipeline);
+    pipeline.set_state(gst::State::Null).expect("Failed to stop pipeline");
+}
+
+#[test]
+fn crypto_mode_negotiation_test() {
+    let offered = ["xsalsa20_poly1305", "xsalsa20_poly1305_lite", "aead_xchacha20_poly1305_rtpsize", "aead_aes256_gcm"];
+    assert_eq!(CryptoMode::negotiate(offered), Some(CryptoMode::XChaCha20Poly1305));
+
+    let offered = ["aead_xchacha20_poly1305_rtpsize", "aead_aes256_gcm_rtpsize"];
+    assert_eq!(CryptoMode::negotiate(offered), Some(CryptoMode::Aes256Gcm));
+
+    let offered = ["xsalsa20_poly1305_suffix", "xsalsa20_poly1305"];
+    assert_eq!(CryptoMode::negotiate(offered), Some(CryptoMode::Suffix));
+
+    assert_eq!(CryptoMode::negotiate(["aead_aes256_gcm", "unknown"]), None);
+    assert_eq!(CryptoMode::negotiate([]), None);
+}
+
+#[test]
+fn crypto_mode_enum_test() {
+    init();
+
+    // The GLib nicks are the names used by Discord, so string setters keep working.
+    let class = glib::EnumClass::new(CryptoMode::static_type()).unwrap();
+    for mode in ALL_CRYPTO_MODES {
+        let name = serde_plain::to_string(&mode).unwrap();
+        let value = class.value_by_nick(&name).expect(&name);
+        assert_eq!(value.value(), mode.into_glib());
+    }
+    assert!(class.value_by_nick("aead_aes256_gcm").is_none());
+
+    let discord_streamer = DiscordStreamer::default();
+    assert_eq!(discord_streamer.property::<CryptoMode>("crypto-mode"), CryptoMode::Lite);
+
+    discord_streamer.set_property_from_str("crypto-mode", "aead_aes256_gcm_rtpsize");
+    assert_eq!(discord_streamer.property::<CryptoMode>("crypto-mode"), CryptoMode::Aes256Gcm);
+
+    discord_streamer.set_property("crypto-mode", CryptoMode::Suffix);
+    assert_eq!(discord_streamer.property::<CryptoMode>("crypto-mode"), CryptoMode::Suffix);
+}
+
+#[test]
+fn available_crypto_modes_test() {
+    init();
+    let discord_streamer = DiscordStreamer::default();
+
+    let offered = gst::Array::new(["aead_aes256_gcm", "xsalsa20_poly1305_lite", "aead_xchacha20_poly1305_rtpsize"]);
+    discord_streamer.set_property("available-crypto-modes", offered.to_value());
+    assert_eq!(discord_streamer.property::<CryptoMode>("crypto-mode"), CryptoMode::XChaCha20Poly1305);
+
+    let available = discord_streamer.property::<gst::Array>("available-crypto-modes");
+    assert_eq!(available.len(), 3);
+
+    // Nothing supported leaves the previous selection in place.
+    discord_streamer.set_property("available-crypto-modes", gst::Array::new(["aead_aes256_gcm"]).to_value());
+    assert_eq!(discord_streamer.property::<CryptoMode>("crypto-mode"), CryptoMode::XChaCha20Poly1305);
+}
+
+#[test]
+fn key_rotation_test() {
+    init();
+    let server = voice_server();
+
+    let pipeline = streaming_pipeline(
+        "videotestsrc is-live=true num-buffers=90 ! video/x-raw,width=1280,height=720 ! videoconvert ! x264enc tune=zerolatency ! discordstreamer name=streamer",
+        &server,
+    );
+
+    pipeline.set_state(gst::State::Playing).expect("Failed to set pipeline state");
+
+    let old_key = [0u8; 32];
+    let new_key = [1u8; 32];
+    let mut packets = receive_frames(&server, 103, 5);
+
+    let discord_streamer = pipeline.by_name("streamer").unwrap();
+    // Properties set while streaming only apply after a restart.
+    discord_streamer.set_property("crypto-mode", CryptoMode::Aes256Gcm);
+    // A key of the wrong size is refused.
+    assert!(!discord_streamer.emit_by_name::<bool>("switch-crypto", &[&CryptoMode::XChaCha20Poly1305, &glib::Bytes::from_static(&[1; 16])]));
+    assert!(discord_streamer.emit_by_name::<bool>("switch-crypto", &[&CryptoMode::XChaCha20Poly1305, &glib::Bytes::from_owned(new_key)]));
+    assert_eq!(discord_streamer.property::<CryptoMode>("crypto-mode"), CryptoMode::XChaCha20Poly1305);
+
+    packets.extend(receive_frames(&server, 103, 20));
+
+    // Every packet is encrypted with exactly one of the keys, switching once at a frame boundary.
+    let rotated: Vec<bool> = packets.iter().map(|packet| {
+        if decrypt_packet(CryptoMode::XChaCha20Poly1305, &new_key, packet).is_ok() {
+            return true;
+        }
+        decrypt_packet(CryptoMode::Lite, &old_key, packet).expect("Packet encrypted with neither key");
+        false
+    }).collect();
+
+    let switch = rotated.iter().position(|&rotated| rotated).expect("Key was never switched");
+    assert!(switch > 0);
+    assert!(rotated[switch..].iter().all(|&rotated| rotated), "Switched back to the old key");
+    assert!(marker(&packets[switch - 1]), "Key switched in the middle of a frame");
+
+    let sequence = |packet: &[u8]| u16::from_be_bytes(packet[2..4].try_into().unwrap());
+    for pair in packets.windows(2) {
+        assert_eq!(sequence(&pair[0]).wrapping_add(1), sequence(&pair[1]));
+    }
+    assert!(timestamp(&packets[switch]).wrapping_sub(timestamp(&packets[switch - 1])) < 90_000);
+    assert_frame_markers(&packets);
+
+    assert_no_error(&pipeline);
+    pipeline.set_state(gst::State::Null).expect("Failed to stop pipeline");
+}
+
+#[test]
+fn crypto_ready_test() {
+    init();
+    let server = voice_server();
+
+    let pipeline = streaming_pipeline(
+        "videotestsrc num-buffers=10 ! videoconvert ! x264enc tune=zerolatency ! discordstreamer name=streamer",
+        &server,
+    );
+
+    // Changes in READY apply when streaming starts.
+    pipeline.set_state(gst::State::Ready).expect("Failed to set pipeline state");
+    let discord_streamer = pipeline.by_name("streamer").unwrap();
+    discord_streamer.set_property("crypto-mode", CryptoMode::XChaCha20Poly1305);
+    discord_streamer.set_property("crypto-key", glib::Bytes::from_static(&[1; 32]).to_value());
+    pipeline.set_state(gst::State::Playing).expect("Failed to set pipeline state");
+
+    for packet in receive_frames(&server, 103, 10) {
+        decrypt_packet(CryptoMode::XChaCha20Poly1305, &[1; 32], &packet).expect("Failed to decrypt packet");
+    }
+
+    assert_no_error(&pipeline);
+    pipeline.set_state(gst::State::Null).expect("Failed to stop pipeline");
+}
+
+#[test]
+fn key_material_test() {
+    init();
+    let discord_streamer = DiscordStreamer::default();
+
+    for name in ["crypto-key", "dave-key"] {
+        let pspec = discord_streamer.find_property(name).unwrap();
+        assert!(!pspec.flags().contains(glib::ParamFlags::READABLE), "{} can be read back", name);
+        assert!(pspec.flags().contains(glib::ParamFlags::WRITABLE));
+    }
+
+    // Neither the key bytes nor their Debug formatting show up in Debug output.
+    let key = [0xAB; 32];
+    let key_pattern = ["171", "ab", "AB"];
+    for mode in ALL_CRYPTO_MODES {
+        let cipher = format!("{:?}", Cipher::new(mode, &key).unwrap());
+        assert!(key_pattern.iter().all(|pattern| !cipher.contains(pattern)), "{}", cipher);
+    }
+
+    let encryptor = format!("{:?}", FrameEncryptor::new(&[0xAB; dave::KEY_SIZE], 3));
+    assert!(key_pattern.iter().all(|pattern| !encryptor.contains(pattern)), "{}", encryptor);
+}
+
+const DAVE_KEY: [u8; dave::KEY_SIZE] = [0x42; dave::KEY_SIZE];
+
+/// An H.264 access unit with SPS, PPS and an IDR slice, the slice header starting
+/// with first_mb_in_slice = 0, slice_type = 2 and pic_parameter_set_id = 0.
+fn h264_access_unit() -> Vec<u8> {
+    let mut frame = hex("000000016742c01fda0140");
+    frame.extend_from_slice(&hex("0000000168ce3c80"));
+    frame.extend_from_slice(&hex("0000000165b8"));
+    frame.extend((0..500u32).map(|i| (i * 7 + 3) as u8 | 1));
+    frame
+}
+
+#[test]
+fn dave_round_trip_test() {
+    let av1 = [
+        hex("1200"),                 // temporal delimiter
+        hex("0a090000002da43ffff9e0"), // sequence header
+        hex("32050102030405"),       // frame
+    ].concat();
+    let frames = [
+        (Codec::Opus, (0..120u8).collect::<Vec<u8>>()),
+        (Codec::H264, h264_access_unit()),
+        (Codec::Vp8, [hex("5003009d012a8002e001"), vec![0x55; 300]].concat()),
+        (Codec::Vp9, vec![0x82; 300]),
+        (Codec::Av1, av1),
+    ];
+
+    let mut encryptor = FrameEncryptor::new(&DAVE_KEY, 0);
+    for (codec, frame) in frames {
+        let encrypted = encryptor.encrypt(codec, &frame).expect("Failed to encrypt frame");
+        assert!(encrypted.ends_with(&[0xFA, 0xFA]), "{:?} frame has no trailer", codec);
+        assert_ne!(&encrypted[..frame.len().min(encrypted.len())], &frame[..], "{:?} frame was not encrypted", codec);
+
+        let decrypted = dave::decrypt_frame(&DAVE_KEY, &encrypted).expect("Failed to decrypt frame");
+        match codec {
+            // Temporal delimiters are dropped and the last OBU loses its size field.
+            Codec::Av1 => assert_eq!(decrypted, hex("0a090000002da43ffff9e0300102030405")),
+            _ => assert_eq!(decrypted, frame, "{:?} round trip failed", codec),
+        }
+    }
+}
+
+#[test]
+fn dave_unencrypted_ranges_test() {
+    let frame = h264_access_unit();
+    let mut encryptor = FrameEncryptor::new(&DAVE_KEY, 0);
+    let encrypted = encryptor.encrypt(Codec::H264, &frame).unwrap();
+
+    // Parameter sets, start codes, NAL headers and the slice header up to the
+    // PPS id stay readable, the slice data is encrypted.
+    let slice_data = frame.len() - 500;
+    assert_eq!(&encrypted[..slice_data], &frame[..slice_data]);
+    assert_ne!(&encrypted[slice_data..frame.len()], &frame[slice_data..]);
+    assert_eq!(encrypted.windows(3).filter(|w| w == &[0, 0, 1]).count(), 3);
+
+    // Opus frames are encrypted entirely.
+    let opus = vec![0xFC; 60];
+    let encrypted = encryptor.encrypt(Codec::Opus, &opus).unwrap();
+    assert!(encrypted[..opus.len()].iter().zip(&opus).any(|(a, b)| a != b));
+}
+
+#[test]
+fn dave_nonce_test() {
+    let mut encryptor = FrameEncryptor::new(&DAVE_KEY, 3);
+    let trailer_nonce = |frame: &[u8]| {
+        // Opus trailer: 8 byte tag, LEB128 nonce, no ranges, size byte and marker.
+        let size = frame[frame.len() - 3] as usize;
+        let fields = &frame[frame.len() - size + 8..frame.len() - 3];
+        fields.iter().rev().fold(0u32, |acc, byte| (acc << 7) | u32::from(byte & 0x7F))
+    };
+
+    let first = trailer_nonce(&encryptor.encrypt(Codec::Opus, &[1; 40]).unwrap());
+    let second = trailer_nonce(&encryptor.encrypt(Codec::Opus, &[1; 40]).unwrap());
+    assert_eq!(first >> 24, 3, "Generation not carried in the nonce");
+    assert_eq!(second, first + 1);
+}
+
+#[test]
+fn dave_h264_start_code_test() {
+    // Three byte start codes and trailing_zero_8bits, which RTP packets do not carry.
+    let frame = [
+        hex("0000016742c01fda0140"),
+        hex("00000168ce3c800000"),
+        hex("0000000165b8"),
+        vec![0x55; 100],
+    ].concat();
+
+    let mut encryptor = FrameEncryptor::new(&DAVE_KEY, 0);
+    let encrypted = encryptor.encrypt(Codec::H264, &frame).unwrap();
+    assert_eq!(encrypted[..4], [0, 0, 0, 1]);
+
+    // The frame is encrypted the way receivers rebuild it.
+    let decrypted = dave::decrypt_frame(&DAVE_KEY, &encrypted).expect("Failed to decrypt frame");
+    assert_eq!(decrypted, [
+        hex("000000016742c01fda0140"),
+        hex("0000000168ce3c80"),
+        hex("0000000165b8"),
+        vec![0x55; 100],
+    ].concat());
+}
+
+#[test]
+fn dave_bad_frame_test() {
+    let mut encryptor = FrameEncryptor::new(&DAVE_KEY, 0);
+    let encrypted = encryptor.encrypt(Codec::H264, &h264_access_unit()).unwrap();
+
+    for index in 0..encrypted.len() - 3 {
+        let mut tampered = encrypted.clone();
+        tampered[index] ^= 0x01;
+        assert!(dave::decrypt_frame(&DAVE_KEY, &tampered).is_err(), "Modified byte {} accepted", index);
+    }
+
+    assert_eq!(dave::decrypt_frame(&[0; dave::KEY_SIZE], &encrypted), Err(dave::DecryptError::BadTag));
+    assert_eq!(dave::decrypt_frame(&DAVE_KEY, &h264_access_unit()), Err(dave::DecryptError::MissingTrailer));
+    assert_eq!(dave::decrypt_frame(&DAVE_KEY, &[0x02, 0xFA, 0xFA]), Err(dave::DecryptError::MalformedTrailer));
+}
+
+#[test]
+fn dave_stream_test() {
+    init();
+    let server = voice_server();
+
+    let pipeline = streaming_pipeline(
+        "discordstreamer name=streamer audiotestsrc num-buffers=30 ! audioconvert ! opusenc ! streamer.audio_sink",
+        &server,
+    );
+    let discord_streamer = pipeline.by_name("streamer").unwrap();
+    discord_streamer.set_property("dave-key", glib::Bytes::from_static(&DAVE_KEY).to_value());
+
+    pipeline.set_state(gst::State::Playing).expect("Failed to set pipeline state");
+
+    // Each Opus frame is sent in one packet, inside the transport encryption.
+    for _ in 0..10 {
+        let packet = receive_rtp(&server, 120);
+        let frame = decrypt_packet(CryptoMode::Lite, &[0; 32], &packet).expect("Failed to decrypt packet");
+        dave::decrypt_frame(&DAVE_KEY, &frame).expect("Failed to decrypt frame");
+    }
+
+    assert_no_error(&pipeline);
+    pipeline.set_state(gst::State::Null).expect("Failed to stop pipeline");
+}
+
+/// Rebuilds access units with four byte start codes from RFC 6184 packets, one
+/// per marker bit.
+fn h264_access_units(packets: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    let mut units = Vec::new();
+    let mut unit = Vec::new();
+
+    for packet in packets {
+        let payload = decrypt_packet(CryptoMode::Lite, &[0; 32], packet).expect("Failed to decrypt packet");
+        match payload[0] & 0x1F {
+            // STAP-A
+            24 => {
+                let mut rest = &payload[1..];
+                while !rest.is_empty() {
+                    let len = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+                    unit.extend_from_slice(&[0, 0, 0, 1]);
+                    unit.extend_from_slice(&rest[2..2 + len]);
+                    rest = &rest[2 + len..];
+                }
+            }
+            // FU-A
+            28 => {
+                if payload[1] & 0x80 != 0 {
+                    unit.extend_from_slice(&[0, 0, 0, 1]);
+                    unit.push((payload[0] & 0xE0) | (payload[1] & 0x1F));
+                }
+                unit.extend_from_slice(&payload[2..]);
+            }
+            _ => {
+                unit.extend_from_slice(&[0, 0, 0, 1]);
+                unit.extend_from_slice(&payload);
+            }
+        }
+
+        if marker(packet) {
+            units.push(std::mem::take(&mut unit));
+        }
+    }
+
+    units
+}
+
+#[test]
+fn dave_h264_stream_test() {
+    init();
+    let server = voice_server();
+
+    // A small MTU spreads the slices over FU-A packets.
+    let pipeline = streaming_pipeline(
+        "videotestsrc num-buffers=10 pattern=snow ! video/x-raw,width=320,height=240 ! videoconvert ! x264enc tune=zerolatency ! discordstreamer name=streamer mtu=300",
+        &server,
+    );
+    let discord_streamer = pipeline.by_name("streamer").unwrap();
+    discord_streamer.set_property("dave-key", glib::Bytes::from_static(&DAVE_KEY).to_value());
+
+    pipeline.set_state(gst::State::Playing).expect("Failed to set pipeline state");
+
+    let units = h264_access_units(&receive_frames(&server, 103, 10));
+    assert_eq!(units.len(), 10);
+    for (index, unit) in units.iter().enumerate() {
+        let frame = dave::decrypt_frame(&DAVE_KEY, unit).expect("Failed to decrypt frame");
+        let nal_types: Vec<u8> = frame.windows(5).filter(|w| w[..4] == [0, 0, 0, 1]).map(|w| w[4] & 0x1F).collect();
+        if index == 0 {
+            assert!(nal_types.contains(&7) && nal_types.contains(&5), "No SPS and IDR slice in {:?}", nal_types);
+        }
+        assert!(nal_types.iter().any(|&nal_type| nal_type == 1 || nal_type == 5), "No slice in {:?}", nal_types);
+    }
+
+    assert_no_error(&pipeline);
+    pipeline.set_state(gst::State::Null).expect("Failed to stop pipeline");
+}
+
+#[test]
+fn dave_vp9_stream_test() {
+    init();
+    let server = voice_server();
+
+    let pipeline = streaming_pipeline(
+        "videotestsrc num-buffers=10 ! video/x-raw,width=320,height=240 ! videoconvert ! vp9enc deadline=1 keyframe-max-dist=5 ! discordstreamer name=streamer",
+        &server,
+    );
+    let discord_streamer = pipeline.by_name("streamer").unwrap();
+    discord_streamer.set_property("dave-key", glib::Bytes::from_static(&DAVE_KEY).to_value());
+
+    pipeline.set_state(gst::State::Playing).expect("Failed to set pipeline state");
+
+    let packets = receive_frames(&server, 107, 10);
+    let payloads: Vec<Vec<u8>> = packets.iter().map(|packet| {
+        decrypt_packet(CryptoMode::Lite, &[0; 32], packet).expect("Failed to decrypt packet")
+    }).collect();
+
+    // The encrypted bitstream is not parsed, keyframes are known from the buffer
+    // flags and the frame size from the caps.
+    let frame_starts: Vec<&Vec<u8>> = payloads.iter().filter(|payload| payload[0] & 0x08 != 0).collect();
+    assert_eq!(frame_starts.len(), 10);
+    let keyframes: Vec<bool> = frame_starts.iter().map(|payload| payload[0] & 0x40 == 0).collect();
+    assert!(keyframes[0] && keyframes.contains(&false), "Unexpected keyframes {:?}", keyframes);
+
+    for (payload, keyframe) in frame_starts.iter().zip(keyframes) {
+        assert_eq!(payload[0] & 0x02 != 0, keyframe, "Scalability structure on the wrong frames");
+        if keyframe {
+            // Y and G bits, then 320x240.
+            assert_eq!(payload[5..10], [0x18, 0x01, 0x40, 0x00, 0xF0]);
+        }
+    }
+
+    assert_no_error(&pipeline);
+    pipeline.set_state(gst::State::Null).expect("Failed to stop pipeline");
+}